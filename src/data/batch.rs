@@ -0,0 +1,444 @@
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::task::AbortOnDropHandle;
+
+use crate::data::spool::Spool;
+use crate::data::Datastore;
+use crate::model::traffic::Traffic;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// What to do with new traffic when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    DropOldest,
+    Block,
+    Spill,
+}
+
+impl Backpressure {
+    pub fn from_config(name: &str) -> Result<Self, Error> {
+        match name {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "block" => Ok(Self::Block),
+            "spill" => Ok(Self::Spill),
+            _ => Err(format!("Unknown [batch] backpressure: {}", name).into()),
+        }
+    }
+}
+
+struct BatchState {
+    datastore: Box<dyn Datastore>,
+    queue: Mutex<VecDeque<Traffic>>,
+    capacity: usize,
+    batch_size: usize,
+    window: Duration,
+    backpressure: Backpressure,
+    spill: Option<Arc<Spool>>,
+    dropped: AtomicU64,
+    // Entries whose batch failed since the last flush, reported by that flush.
+    failed: AtomicU64,
+    // Held while a batch is being written, so a flush can wait for the writer task's batch.
+    writing: tokio::sync::Mutex<()>,
+    batch_ready: Notify,
+    not_full: Notify,
+}
+
+// Queue traffic in a bounded buffer and write it to the datastore in batches.
+// A writer task sends a batch once max_batch_size entries are waiting or the window elapses.
+// Auth info is rare and passes straight through.
+pub struct Batcher {
+    state: Arc<BatchState>,
+    // The writer task holds the state too, so it has to be stopped when the Batcher is dropped.
+    _writer: AbortOnDropHandle<()>,
+}
+
+#[async_trait]
+impl Datastore for Batcher {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let not_full = self.state.not_full.notified();
+            {
+                let mut queue = self.state.queue.lock().unwrap();
+                if queue.len() < self.state.capacity {
+                    queue.push_back(traffic.clone());
+                    if queue.len() >= self.state.batch_size {
+                        self.state.batch_ready.notify_one();
+                    }
+                    return Ok(());
+                }
+                match self.state.backpressure {
+                    Backpressure::DropOldest => {
+                        queue.pop_front();
+                        queue.push_back(traffic.clone());
+                        let dropped = self.state.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                        if dropped.is_power_of_two() {
+                            eprintln!(
+                                "[ERROR] [src/data/batch.rs] [add_traffic]: (queue full) {} entries dropped so far",
+                                dropped
+                            );
+                        }
+                        self.state.batch_ready.notify_one();
+                        return Ok(());
+                    }
+                    Backpressure::Spill => break,
+                    Backpressure::Block => {}
+                }
+            }
+            not_full.await;
+        }
+        self.spill(traffic).await
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        self.state.datastore.add_authinfo(auth).await
+    }
+    // One per closed connection - too few to be worth batching.
+    async fn add_websocket_session(
+        &self,
+        session: &crate::model::websocket::WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.state.datastore.add_websocket_session(session).await
+    }
+    async fn apply_retention(
+        &self,
+        policy: &crate::data::retention::Policy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        self.state.datastore.apply_retention(policy).await
+    }
+    async fn find_traffic(
+        &self,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        self.state.datastore.find_traffic(query).await
+    }
+    async fn distinct_traffic(
+        &self,
+        field: crate::data::query::Field,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.state.datastore.distinct_traffic(field, query).await
+    }
+    // Write everything that is queued right now, then flush the datastore behind the queue.
+    // Fails if any batch since the last flush could not be written, including this one's.
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        while Self::write_batch(&self.state).await > 0 {}
+        self.state.datastore.flush().await?;
+        match self.state.failed.swap(0, Ordering::Relaxed) {
+            0 => Ok(()),
+            failed => Err(format!("{} batched entries failed to write", failed).into()),
+        }
+    }
+}
+
+impl Batcher {
+    pub fn new(
+        datastore: Box<dyn Datastore>,
+        capacity: usize,
+        batch_size: usize,
+        window: Duration,
+        backpressure: Backpressure,
+        spill: Option<Arc<Spool>>,
+    ) -> Result<Self, Error> {
+        // An empty queue could never take traffic, and empty batches would never drain it.
+        if capacity == 0 {
+            return Err("[batch] capacity must be at least 1.".into());
+        }
+        if batch_size == 0 {
+            return Err("[batch] max_batch_size must be at least 1.".into());
+        }
+        if backpressure == Backpressure::Spill && spill.is_none() {
+            return Err("[batch] backpressure = \"spill\" requires a [spool] section.".into());
+        }
+        let state = Arc::new(BatchState {
+            datastore,
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            batch_size,
+            window,
+            backpressure,
+            spill,
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            writing: tokio::sync::Mutex::new(()),
+            batch_ready: Notify::new(),
+            not_full: Notify::new(),
+        });
+        let writer = AbortOnDropHandle::new(tokio::spawn(Self::write_batches(state.clone())));
+        Ok(Self {
+            state,
+            _writer: writer,
+        })
+    }
+
+    // Spool traffic the full queue has no room for. Everything already queued is older, so it is
+    // moved to the spool first - once the batch being written finishes - to keep replay in order.
+    async fn spill(&self, traffic: &Traffic) -> Result<(), Box<dyn std::error::Error>> {
+        let spool = self
+            .state
+            .spill
+            .as_ref()
+            .expect("Spilling requires a spool.");
+        let _writing = self.state.writing.lock().await;
+        let queued: Vec<Traffic> = self.state.queue.lock().unwrap().drain(..).collect();
+        self.state.not_full.notify_waiters();
+        for queued in &queued {
+            spool.spill_traffic(queued).await?;
+        }
+        spool.spill_traffic(traffic).await
+    }
+
+    async fn write_batches(state: Arc<BatchState>) {
+        loop {
+            tokio::select! {
+                _ = state.batch_ready.notified() => {},
+                _ = tokio::time::sleep(state.window) => {},
+            }
+            while Self::write_batch(&state).await == state.batch_size {}
+        }
+    }
+
+    // Take up to batch_size entries off the queue and write them, returning how many were taken.
+    async fn write_batch(state: &BatchState) -> usize {
+        let _writing = state.writing.lock().await;
+        let batch: Vec<Traffic> = {
+            let mut queue = state.queue.lock().unwrap();
+            let count = queue.len().min(state.batch_size);
+            queue.drain(..count).collect()
+        };
+        if batch.is_empty() {
+            return 0;
+        }
+        state.not_full.notify_waiters();
+        let result = state
+            .datastore
+            .add_traffic_batch(&batch)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = result {
+            state
+                .failed
+                .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            eprintln!(
+                "[ERROR] [src/data/batch.rs] [write_batch]: ({} entries) {}",
+                batch.len(),
+                e
+            );
+        }
+        batch.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::auth::AuthInfo;
+
+    // A datastore that records every batch it was given.
+    struct Recorder {
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    #[async_trait]
+    impl Datastore for Recorder {
+        async fn add_traffic(&self, traffic: &Traffic) -> Result<(), Box<dyn std::error::Error>> {
+            self.add_traffic_batch(std::slice::from_ref(traffic)).await
+        }
+        async fn add_traffic_batch(
+            &self,
+            traffic: &[Traffic],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            let paths = traffic.iter().map(|t| t.path.clone()).collect();
+            self.batches.lock().unwrap().push(paths);
+            Ok(())
+        }
+        async fn add_authinfo(&self, _auth: &AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_by_size() -> Result<(), Box<dyn std::error::Error>> {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let batcher = Batcher::new(
+            Box::new(Recorder {
+                batches: batches.clone(),
+            }),
+            10,
+            2,
+            Duration::from_secs(60),
+            Backpressure::Block,
+            None,
+        )
+        .unwrap();
+        for path in ["/1", "/2"] {
+            batcher
+                .add_traffic(&Traffic::fixture("foobar.com", path))
+                .await?;
+        }
+        for _ in 0..100 {
+            if !batches.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*batches.lock().unwrap(), vec![vec!["/1", "/2"]]);
+
+        // Under max_batch_size, so it waits for the window - or a flush.
+        batcher
+            .add_traffic(&Traffic::fixture("foobar.com", "/3"))
+            .await?;
+        batcher.flush().await?;
+        assert_eq!(batches.lock().unwrap()[1], vec!["/3"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejects_empty_queue_and_batches() {
+        for (capacity, batch_size) in [(0, 10), (10, 0)] {
+            let batcher = Batcher::new(
+                Box::new(Recorder {
+                    batches: Arc::new(Mutex::new(Vec::new())),
+                }),
+                capacity,
+                batch_size,
+                Duration::from_secs(60),
+                Backpressure::Block,
+                None,
+            );
+            assert!(batcher.is_err());
+        }
+        assert!(Backpressure::from_config("drop_newest").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drop_stops_writer() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let batcher = Batcher::new(
+            Box::new(Recorder {
+                batches: batches.clone(),
+            }),
+            10,
+            10,
+            Duration::from_secs(60),
+            Backpressure::Block,
+            None,
+        )
+        .unwrap();
+        drop(batcher);
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&batches), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() -> Result<(), Box<dyn std::error::Error>> {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let batcher = Batcher::new(
+            Box::new(Recorder {
+                batches: batches.clone(),
+            }),
+            2,
+            10,
+            Duration::from_secs(60),
+            Backpressure::DropOldest,
+            None,
+        )
+        .unwrap();
+        for path in ["/1", "/2", "/3"] {
+            batcher
+                .add_traffic(&Traffic::fixture("foobar.com", path))
+                .await?;
+        }
+        batcher.flush().await?;
+        assert_eq!(*batches.lock().unwrap(), vec![vec!["/2", "/3"]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_reports_failed_batch() {
+        struct Down;
+
+        #[async_trait]
+        impl Datastore for Down {
+            async fn add_traffic(
+                &self,
+                _traffic: &Traffic,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                Err("connection refused".into())
+            }
+            async fn add_authinfo(
+                &self,
+                _auth: &AuthInfo,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+        }
+
+        let batcher = Batcher::new(
+            Box::new(Down),
+            10,
+            10,
+            Duration::from_secs(60),
+            Backpressure::Block,
+            None,
+        )
+        .unwrap();
+        batcher
+            .add_traffic(&Traffic::fixture("foobar.com", "/1"))
+            .await
+            .unwrap();
+        let error = batcher.flush().await.unwrap_err();
+        assert_eq!(error.to_string(), "1 batched entries failed to write");
+        // Reported once.
+        assert!(batcher.flush().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_spill_keeps_order() -> Result<(), Box<dyn std::error::Error>> {
+        let directory =
+            std::env::temp_dir().join(format!("ohm-batch-spill-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let spool = Arc::new(Spool::new(
+            Box::new(Recorder {
+                batches: batches.clone(),
+            }),
+            directory.to_str().unwrap(),
+            1 << 20,
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            3,
+        ));
+        let batcher = Batcher::new(
+            Box::new(spool.clone()),
+            1,
+            10,
+            Duration::from_secs(60),
+            Backpressure::Spill,
+            Some(spool),
+        )
+        .unwrap();
+        // "/1" waits in the queue, so "/2" has to spill - and "/1" must reach the store first.
+        for path in ["/1", "/2"] {
+            batcher
+                .add_traffic(&Traffic::fixture("foobar.com", path))
+                .await?;
+        }
+        batcher.flush().await?;
+        for _ in 0..100 {
+            if batches.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*batches.lock().unwrap(), vec![vec!["/1"], vec!["/2"]]);
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+
+use crate::data::Datastore;
+use crate::model::traffic::{BodyRef, Traffic};
+
+// Somewhere to keep bodies by the SHA-256 of their contents.
+#[async_trait]
+pub trait BodyStore: Send + Sync {
+    async fn put_body(&self, sha256: &str, body: &[u8]) -> Result<(), Box<dyn std::error::Error>>;
+    async fn get_body(&self, sha256: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    // The name a body is stored and referenced under.
+    fn address(&self, body: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(sha256_hex(body))
+    }
+}
+
+// Bodies as files under "{directory}/{first two hex digits}/{sha256}".
+pub struct BlobDirectory {
+    directory: PathBuf,
+}
+
+#[async_trait]
+impl BodyStore for BlobDirectory {
+    async fn put_body(&self, sha256: &str, body: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.blob_path(sha256);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(()); // Already stored - that's the point.
+        }
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let temporary_path = path.with_extension("tmp");
+        tokio::fs::write(&temporary_path, body).await?;
+        tokio::fs::rename(&temporary_path, &path).await?;
+        Ok(())
+    }
+    async fn get_body(&self, sha256: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(tokio::fs::read(self.blob_path(sha256)).await?)
+    }
+}
+
+impl BlobDirectory {
+    pub fn new(directory: &str) -> Self {
+        Self {
+            directory: PathBuf::from(directory),
+        }
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.directory.join(&sha256[..2]).join(sha256)
+    }
+}
+
+// Move bodies above a size threshold out of traffic documents and into a body store.
+// The traffic keeps a BodyRef with the hash and size, and rehydrate() puts the body back.
+pub struct ContentAddressed {
+    datastore: Box<dyn Datastore>,
+    bodies: Box<dyn BodyStore>,
+    threshold: usize,
+}
+
+#[async_trait]
+impl Datastore for ContentAddressed {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut traffic = traffic.clone();
+        self.externalize(&mut traffic).await?;
+        self.datastore.add_traffic(&traffic).await
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        self.datastore.add_authinfo(auth).await
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &crate::model::websocket::WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.datastore.add_websocket_session(session).await
+    }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut traffic = traffic.to_vec();
+        for t in traffic.iter_mut() {
+            self.externalize(t).await?;
+        }
+        self.datastore.add_traffic_batch(&traffic).await
+    }
+    async fn apply_retention(
+        &self,
+        policy: &crate::data::retention::Policy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        self.datastore.apply_retention(policy).await
+    }
+    async fn find_traffic(
+        &self,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        let mut traffic = self.datastore.find_traffic(query).await?;
+        for t in traffic.iter_mut() {
+            self.rehydrate(t).await?;
+        }
+        Ok(traffic)
+    }
+    async fn distinct_traffic(
+        &self,
+        field: crate::data::query::Field,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.datastore.distinct_traffic(field, query).await
+    }
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.datastore.flush().await
+    }
+}
+
+impl ContentAddressed {
+    pub fn new(
+        datastore: Box<dyn Datastore>,
+        bodies: Box<dyn BodyStore>,
+        threshold: usize,
+    ) -> Self {
+        Self {
+            datastore,
+            bodies,
+            threshold,
+        }
+    }
+
+    pub async fn externalize(
+        &self,
+        traffic: &mut Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if traffic.request_body.len() > self.threshold {
+            let body_ref = self.put(&traffic.request_body).await?;
+            traffic.request_body = Vec::new();
+            traffic.request_body_string = None;
+            traffic.request_body_ref = Some(body_ref);
+        }
+        if traffic.response_body.len() > self.threshold {
+            let body_ref = self.put(&traffic.response_body).await?;
+            traffic.response_body = Vec::new();
+            traffic.response_body_string = None;
+            traffic.response_body_ref = Some(body_ref);
+        }
+        Ok(())
+    }
+
+    pub async fn rehydrate(&self, traffic: &mut Traffic) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(body_ref) = traffic.request_body_ref.take() {
+            traffic.request_body = self.bodies.get_body(&body_ref.sha256).await?;
+            traffic.request_body_string = std::str::from_utf8(&traffic.request_body)
+                .ok()
+                .map(|body| body.to_string());
+        }
+        if let Some(body_ref) = traffic.response_body_ref.take() {
+            traffic.response_body = self.bodies.get_body(&body_ref.sha256).await?;
+            traffic.response_body_string = std::str::from_utf8(&traffic.response_body)
+                .ok()
+                .map(|body| body.to_string());
+        }
+        Ok(())
+    }
+
+    async fn put(&self, body: &[u8]) -> Result<BodyRef, Box<dyn std::error::Error>> {
+        let sha256 = self.bodies.address(body)?;
+        self.bodies.put_body(&sha256, body).await?;
+        Ok(BodyRef {
+            sha256,
+            size: body.len() as u64,
+        })
+    }
+}
+
+pub fn sha256_hex(body: &[u8]) -> String {
+    openssl::sha::sha256(body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::auth::AuthInfo;
+    use std::sync::{Arc, Mutex};
+
+    // A datastore that keeps the last traffic it was given.
+    struct Last {
+        traffic: Arc<Mutex<Option<Traffic>>>,
+    }
+
+    #[async_trait]
+    impl Datastore for Last {
+        async fn add_traffic(&self, traffic: &Traffic) -> Result<(), Box<dyn std::error::Error>> {
+            *self.traffic.lock().unwrap() = Some(traffic.clone());
+            Ok(())
+        }
+        async fn add_authinfo(&self, _auth: &AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_externalize_and_rehydrate() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = std::env::temp_dir().join(format!("ohm-bodies-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let stored = Arc::new(Mutex::new(None));
+        let content_addressed = ContentAddressed::new(
+            Box::new(Last {
+                traffic: stored.clone(),
+            }),
+            Box::new(BlobDirectory::new(directory.to_str().unwrap())),
+            8,
+        );
+        let traffic = Traffic {
+            method: "POST".to_string(),
+            request_body: b"PING!".to_vec(),
+            request_body_string: Some("PING!".to_string()),
+            response_body: b"a large javascript bundle".to_vec(),
+            response_body_string: Some("a large javascript bundle".to_string()),
+            ..Traffic::fixture("foobar.com", "/")
+        };
+
+        content_addressed.add_traffic(&traffic).await?;
+        let mut stored = stored.lock().unwrap().take().unwrap();
+        assert_eq!(stored.request_body, b"PING!".to_vec());
+        assert!(stored.request_body_ref.is_none());
+        assert!(stored.response_body.is_empty());
+        assert_eq!(
+            stored.response_body_ref,
+            Some(BodyRef {
+                sha256: sha256_hex(b"a large javascript bundle"),
+                size: 25,
+            })
+        );
+
+        content_addressed.rehydrate(&mut stored).await?;
+        assert_eq!(stored, traffic);
+        assert_eq!(stored.response_body_string, traffic.response_body_string);
+
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
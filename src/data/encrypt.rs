@@ -0,0 +1,500 @@
+use async_trait::async_trait;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::sync::Arc;
+
+use crate::data::bodies::BodyStore;
+use crate::data::query::{Field, TrafficQuery};
+use crate::data::Datastore;
+use crate::model::traffic::Traffic;
+use crate::model::websocket::{WebSocketSession, OPCODE_TEXT};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// Encrypted header values are stored as text with this prefix.
+const HEADER_PREFIX: &str = "ohm-enc:v1:";
+
+type Error = Box<dyn std::error::Error>;
+
+// AES-256-GCM with a random nonce per value, stored as nonce || ciphertext || tag.
+// The field name is authenticated too, so a value can't be moved to another field.
+pub struct FieldCipher {
+    key: Vec<u8>,
+}
+
+impl FieldCipher {
+    // The key file holds 32 bytes, either raw or hex encoded (`openssl rand -hex 32`).
+    pub fn from_key_file(key_path: &str) -> Result<Self, Error> {
+        let contents = std::fs::read(key_path)?;
+        let key = match std::str::from_utf8(&contents).map(str::trim) {
+            Ok(hex) if hex.len() == 64 => (0..64)
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()?,
+            _ => contents,
+        };
+        match key.len() {
+            32 => Ok(Self { key }),
+            _ => Err(format!("{} must contain a 32 byte key", key_path).into()),
+        }
+    }
+
+    pub fn encrypt(&self, field: &str, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            field.as_bytes(),
+            plaintext,
+            &mut tag,
+        )?;
+        Ok([&nonce[..], &ciphertext, &tag].concat())
+    }
+
+    pub fn decrypt(&self, field: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(format!("{} is too short to be encrypted", field).into());
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        Ok(decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            field.as_bytes(),
+            ciphertext,
+            tag,
+        )?)
+    }
+
+    // HMAC-SHA256 under the key, hex encoded - a stable name that only the key holder can link
+    // back to the value.
+    pub fn digest(&self, value: &[u8]) -> Result<String, Error> {
+        let key = PKey::hmac(&self.key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(value)?;
+        Ok(signer
+            .sign_to_vec()?
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+}
+
+// Encrypt configured header values and bodies, leaving everything else searchable.
+// Encrypted fields are listed in Traffic.encrypted_fields, e.g. "request_headers.cookie".
+pub struct Encryptor {
+    cipher: FieldCipher,
+    headers: Vec<String>,
+    bodies: bool,
+}
+
+impl Encryptor {
+    pub fn new(cipher: FieldCipher, headers: &[String], bodies: bool) -> Self {
+        Self {
+            cipher,
+            headers: headers.iter().map(|h| h.to_lowercase()).collect(),
+            bodies,
+        }
+    }
+
+    pub fn encrypt_traffic(&self, traffic: &mut Traffic) -> Result<(), Error> {
+        for header in &self.headers {
+            for (section, headers) in [
+                ("request_headers", &mut traffic.request_headers),
+                ("response_headers", &mut traffic.response_headers),
+            ] {
+                if let Some(value) = headers.get_mut(header) {
+                    let field = format!("{}.{}", section, header);
+                    let sealed = self.cipher.encrypt(&field, value.as_bytes())?;
+                    *value = format!(
+                        "{}{}",
+                        HEADER_PREFIX,
+                        openssl::base64::encode_block(&sealed)
+                    );
+                    traffic.encrypted_fields.push(field);
+                }
+            }
+        }
+        // A body moved out to a [bodies] store is sealed there by EncryptedBodies instead.
+        if self.bodies && traffic.request_body_ref.is_none() {
+            traffic.request_body = self.cipher.encrypt("request_body", &traffic.request_body)?;
+            traffic.request_body_string = None;
+            traffic.encrypted_fields.push("request_body".to_string());
+        }
+        if self.bodies && traffic.response_body_ref.is_none() {
+            traffic.response_body = self
+                .cipher
+                .encrypt("response_body", &traffic.response_body)?;
+            traffic.response_body_string = None;
+            traffic.encrypted_fields.push("response_body".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn encrypts_bodies(&self) -> bool {
+        self.bodies
+    }
+
+    // One exported record - a WebSocket session if it has messages, traffic otherwise.
+    pub fn decrypt_line(&self, line: &str) -> Result<String, Error> {
+        let record: serde_json::Value = serde_json::from_str(line)?;
+        if record.get("messages").is_some() {
+            let mut session: WebSocketSession = serde_json::from_value(record)?;
+            self.decrypt_websocket_session(&mut session)?;
+            return Ok(serde_json::to_string(&session)?);
+        }
+        let mut traffic: Traffic = serde_json::from_value(record)?;
+        self.decrypt_traffic(&mut traffic)?;
+        Ok(traffic.get_json())
+    }
+
+    // A blob from a [bodies] store, sealed under the address it is stored at.
+    pub fn decrypt_body(&self, address: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        self.cipher.decrypt(&format!("bodies.{}", address), sealed)
+    }
+
+    // With bodies, frame payloads are sealed too - each under its position in the session.
+    pub fn encrypt_websocket_session(&self, session: &mut WebSocketSession) -> Result<(), Error> {
+        if !self.bodies {
+            return Ok(());
+        }
+        for (index, message) in session.messages.iter_mut().enumerate() {
+            let field = format!("messages.{}.payload", index);
+            message.payload = self.cipher.encrypt(&field, &message.payload)?;
+            message.payload_string = None;
+        }
+        session
+            .encrypted_fields
+            .push("messages.payload".to_string());
+        Ok(())
+    }
+
+    pub fn decrypt_websocket_session(&self, session: &mut WebSocketSession) -> Result<(), Error> {
+        for field in std::mem::take(&mut session.encrypted_fields) {
+            if field != "messages.payload" {
+                return Err(format!("unknown encrypted field {}", field).into());
+            }
+            for (index, message) in session.messages.iter_mut().enumerate() {
+                let field = format!("messages.{}.payload", index);
+                message.payload = self.cipher.decrypt(&field, &message.payload)?;
+                message.payload_string = match message.opcode {
+                    OPCODE_TEXT => std::str::from_utf8(&message.payload)
+                        .ok()
+                        .map(str::to_string),
+                    _ => None,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    pub fn decrypt_traffic(&self, traffic: &mut Traffic) -> Result<(), Error> {
+        for field in std::mem::take(&mut traffic.encrypted_fields) {
+            match field.split_once('.') {
+                Some((section, header)) => {
+                    let headers: &mut HashMap<String, String> = match section {
+                        "request_headers" => &mut traffic.request_headers,
+                        "response_headers" => &mut traffic.response_headers,
+                        _ => return Err(format!("unknown encrypted field {}", field).into()),
+                    };
+                    if let Some(value) = headers.get_mut(header) {
+                        let encoded = value.strip_prefix(HEADER_PREFIX).unwrap_or(value);
+                        let sealed = openssl::base64::decode_block(encoded)?;
+                        *value = String::from_utf8(self.cipher.decrypt(&field, &sealed)?)?;
+                    }
+                }
+                None => {
+                    let (body, body_string) = match field.as_str() {
+                        "request_body" => {
+                            (&mut traffic.request_body, &mut traffic.request_body_string)
+                        }
+                        "response_body" => (
+                            &mut traffic.response_body,
+                            &mut traffic.response_body_string,
+                        ),
+                        _ => return Err(format!("unknown encrypted field {}", field).into()),
+                    };
+                    *body = self.cipher.decrypt(&field, body)?;
+                    *body_string = std::str::from_utf8(body).ok().map(|b| b.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Encrypt traffic on its way into the wrapped datastore.
+pub struct Encrypted {
+    datastore: Box<dyn Datastore>,
+    encryptor: Arc<Encryptor>,
+}
+
+#[async_trait]
+impl Datastore for Encrypted {
+    async fn add_traffic(&self, traffic: &crate::Traffic) -> Result<(), Error> {
+        let mut traffic = traffic.clone();
+        self.encryptor.encrypt_traffic(&mut traffic)?;
+        self.datastore.add_traffic(&traffic).await
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Error> {
+        self.datastore.add_authinfo(auth).await
+    }
+    async fn add_websocket_session(&self, session: &WebSocketSession) -> Result<(), Error> {
+        let mut session = session.clone();
+        self.encryptor.encrypt_websocket_session(&mut session)?;
+        self.datastore.add_websocket_session(&session).await
+    }
+    async fn add_traffic_batch(&self, traffic: &[crate::Traffic]) -> Result<(), Error> {
+        let mut traffic = traffic.to_vec();
+        for t in traffic.iter_mut() {
+            self.encryptor.encrypt_traffic(t)?;
+        }
+        self.datastore.add_traffic_batch(&traffic).await
+    }
+    async fn apply_retention(&self, policy: &crate::data::retention::Policy) -> Result<u64, Error> {
+        self.datastore.apply_retention(policy).await
+    }
+    // Ohm holds the key, so whatever it reads back is decrypted.
+    async fn find_traffic(&self, query: &TrafficQuery) -> Result<Vec<crate::Traffic>, Error> {
+        let mut traffic = self.datastore.find_traffic(query).await?;
+        for t in traffic.iter_mut() {
+            self.encryptor.decrypt_traffic(t)?;
+        }
+        Ok(traffic)
+    }
+    async fn distinct_traffic(
+        &self,
+        field: Field,
+        query: &TrafficQuery,
+    ) -> Result<Vec<String>, Error> {
+        self.datastore.distinct_traffic(field, query).await
+    }
+    async fn flush(&self) -> Result<(), Error> {
+        self.datastore.flush().await
+    }
+}
+
+impl Encrypted {
+    pub fn new(datastore: Box<dyn Datastore>, encryptor: Arc<Encryptor>) -> Self {
+        Self {
+            datastore,
+            encryptor,
+        }
+    }
+}
+
+// Seal bodies in a [bodies] store. They are addressed by an HMAC-SHA256 of the plaintext under
+// the data key, so identical bodies are stored once even though every seal uses a fresh nonce,
+// and the address can't be checked against a guessed body without the key.
+pub struct EncryptedBodies {
+    bodies: Box<dyn BodyStore>,
+    encryptor: Arc<Encryptor>,
+}
+
+#[async_trait]
+impl BodyStore for EncryptedBodies {
+    async fn put_body(&self, sha256: &str, body: &[u8]) -> Result<(), Error> {
+        let sealed = self
+            .encryptor
+            .cipher
+            .encrypt(&format!("bodies.{}", sha256), body)?;
+        self.bodies.put_body(sha256, &sealed).await
+    }
+    async fn get_body(&self, sha256: &str) -> Result<Vec<u8>, Error> {
+        let sealed = self.bodies.get_body(sha256).await?;
+        self.encryptor.decrypt_body(sha256, &sealed)
+    }
+    fn address(&self, body: &[u8]) -> Result<String, Error> {
+        self.encryptor.cipher.digest(body)
+    }
+}
+
+impl EncryptedBodies {
+    pub fn new(bodies: Box<dyn BodyStore>, encryptor: Arc<Encryptor>) -> Self {
+        Self { bodies, encryptor }
+    }
+}
+
+// `ohm decrypt <key file>` - decrypt traffic and WebSocket session JSON Lines from stdin to
+// stdout, e.g. a JSONL capture or `mongoexport --collection traffic` output.
+pub fn decrypt_command(key_path: &str) -> Result<(), Error> {
+    let encryptor = Encryptor::new(FieldCipher::from_key_file(key_path)?, &[], false);
+    let stdout = std::io::stdout();
+    let mut output = stdout.lock();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(output, "{}", encryptor.decrypt_line(&line)?)?;
+    }
+    Ok(())
+}
+
+// `ohm decrypt <key file> --blob <address>` - decrypt one [bodies] blob from stdin to stdout,
+// e.g. a file from the body directory, which is named by its address.
+pub fn decrypt_blob_command(key_path: &str, address: &str) -> Result<(), Error> {
+    let encryptor = Encryptor::new(FieldCipher::from_key_file(key_path)?, &[], false);
+    let mut sealed = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut sealed)?;
+    std::io::stdout()
+        .lock()
+        .write_all(&encryptor.decrypt_body(address, &sealed)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> FieldCipher {
+        FieldCipher { key: vec![7; 32] }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_traffic() -> Result<(), Error> {
+        let headers = ["Cookie".to_string(), "set-cookie".to_string()];
+        let encryptor = Encryptor::new(cipher(), &headers, true);
+        let original = Traffic {
+            request_headers: HashMap::from([
+                ("cookie".to_string(), "session=secret".to_string()),
+                ("host".to_string(), "foobar.com".to_string()),
+            ]),
+            response_headers: HashMap::from([(
+                "set-cookie".to_string(),
+                "session=rotated".to_string(),
+            )]),
+            response_body: b"PONG!".to_vec(),
+            response_body_string: Some("PONG!".to_string()),
+            ..Traffic::fixture("foobar.com", "/account")
+        };
+        let mut traffic = original.clone();
+
+        encryptor.encrypt_traffic(&mut traffic)?;
+        assert!(traffic.request_headers["cookie"].starts_with(HEADER_PREFIX));
+        assert!(traffic.response_headers["set-cookie"].starts_with(HEADER_PREFIX));
+        assert_eq!(traffic.request_headers["host"], "foobar.com");
+        assert_eq!(traffic.path, "/account");
+        assert_ne!(traffic.response_body, original.response_body);
+        assert_eq!(traffic.response_body_string, None);
+
+        // Whoever decrypts only needs the key - the fields are listed in the traffic itself.
+        let reader = Encryptor::new(cipher(), &[], false);
+        let mut traffic: Traffic = serde_json::from_str(&traffic.get_json())?;
+        reader.decrypt_traffic(&mut traffic)?;
+        assert_eq!(traffic, original);
+        assert_eq!(traffic.response_body_string, original.response_body_string);
+        assert!(traffic.encrypted_fields.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_lines() -> Result<(), Error> {
+        use crate::model::websocket::{Direction, WebSocketMessage};
+
+        let encryptor = Encryptor::new(cipher(), &["cookie".to_string()], true);
+        let mut session = WebSocketSession::new(&Traffic::fixture("foobar.com", "/chat"));
+        session.messages = vec![
+            WebSocketMessage::new(
+                Direction::ClientToServer,
+                OPCODE_TEXT,
+                true,
+                b"hello".to_vec(),
+            ),
+            WebSocketMessage::new(Direction::ServerToClient, 0x2, true, vec![0, 1, 2]),
+        ];
+        let original = session.clone();
+        encryptor.encrypt_websocket_session(&mut session)?;
+        assert_ne!(session.messages[0].payload, b"hello".to_vec());
+
+        let reader = Encryptor::new(cipher(), &[], false);
+        let decrypted: WebSocketSession =
+            serde_json::from_str(&reader.decrypt_line(&serde_json::to_string(&session)?)?)?;
+        assert_eq!(decrypted, original);
+
+        let mut traffic = Traffic {
+            request_headers: HashMap::from([("cookie".to_string(), "session=secret".to_string())]),
+            ..Traffic::fixture("foobar.com", "/account")
+        };
+        encryptor.encrypt_traffic(&mut traffic)?;
+        let decrypted: Traffic = serde_json::from_str(&reader.decrypt_line(&traffic.get_json())?)?;
+        assert_eq!(decrypted.request_headers["cookie"], "session=secret");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_rejects_moved_value() -> Result<(), Error> {
+        let sealed = cipher().encrypt("request_headers.cookie", b"session=secret")?;
+        assert!(cipher().decrypt("request_headers.cookie", &sealed).is_ok());
+        assert!(cipher()
+            .decrypt("response_headers.set-cookie", &sealed)
+            .is_err());
+        assert!(FieldCipher { key: vec![8; 32] }
+            .decrypt("request_headers.cookie", &sealed)
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_bodies_are_deduplicated() -> Result<(), Error> {
+        use crate::data::bodies::{sha256_hex, BlobDirectory, ContentAddressed};
+        use crate::data::memory::Memory;
+
+        let directory =
+            std::env::temp_dir().join(format!("ohm-encrypted-bodies-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let encryptor = Arc::new(Encryptor::new(cipher(), &["cookie".to_string()], true));
+        let memory = Arc::new(Memory::new(10));
+        // The order with_write_path builds: bodies move out, then the rest is encrypted.
+        let datastore = ContentAddressed::new(
+            Box::new(Encrypted::new(Box::new(memory.clone()), encryptor.clone())),
+            Box::new(EncryptedBodies::new(
+                Box::new(BlobDirectory::new(directory.to_str().unwrap())),
+                encryptor,
+            )),
+            4,
+        );
+        let original = Traffic {
+            request_headers: HashMap::from([("cookie".to_string(), "session=secret".to_string())]),
+            response_body: b"PONG!".to_vec(),
+            response_body_string: Some("PONG!".to_string()),
+            ..Traffic::fixture("foobar.com", "/account")
+        };
+        datastore.add_traffic(&original).await?;
+        datastore.add_traffic(&original).await?;
+
+        // Named by the keyed digest, so the plain SHA-256 of a guessed body doesn't find it.
+        let address = cipher().digest(b"PONG!")?;
+        assert_ne!(address, sha256_hex(b"PONG!"));
+        let blobs = std::fs::read_dir(directory.join(&address[..2]))?.count();
+        assert_eq!(blobs, 1);
+        let blob = std::fs::read(directory.join(&address[..2]).join(&address))?;
+        assert_ne!(blob, b"PONG!".to_vec());
+        let reader = Encryptor::new(cipher(), &[], false);
+        assert_eq!(reader.decrypt_body(&address, &blob)?, b"PONG!".to_vec());
+
+        // The body left a reference behind, so it isn't listed as encrypted in the traffic.
+        let stored = memory.recent_traffic(1).remove(0);
+        assert!(stored.response_body_ref.is_some());
+        assert!(!stored
+            .encrypted_fields
+            .contains(&"response_body".to_string()));
+        let mut exported: Traffic = serde_json::from_str(&stored.get_json())?;
+        Encryptor::new(cipher(), &[], false).decrypt_traffic(&mut exported)?;
+        assert_eq!(exported.request_headers["cookie"], "session=secret");
+
+        let read = datastore.find_traffic(&TrafficQuery::default()).await?;
+        assert_eq!(read[0], original);
+
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+
+use crate::data::Datastore;
+
+// A datastore in the fan-out, with its own host allow/deny subset.
+pub struct Sink {
+    pub name: String,
+    pub datastore: Box<dyn Datastore>,
+    pub allow_list_hosts: Vec<String>,
+    pub deny_list_hosts: Vec<String>,
+}
+
+impl Sink {
+    pub fn new(name: &str, datastore: Box<dyn Datastore>) -> Self {
+        Self {
+            name: name.to_string(),
+            datastore,
+            allow_list_hosts: Vec::new(),
+            deny_list_hosts: Vec::new(),
+        }
+    }
+
+    // Same semantics as the [filter] host lists - an empty allow list passes everything.
+    pub fn accepts_host(&self, host: &str) -> bool {
+        if self
+            .deny_list_hosts
+            .iter()
+            .any(|denied_host| host.contains(denied_host))
+        {
+            return false;
+        }
+        self.allow_list_hosts.is_empty()
+            || self
+                .allow_list_hosts
+                .iter()
+                .any(|allowed_host| host.contains(allowed_host))
+    }
+}
+
+// Each kind of store is configured by its own section ([db], [jsonl], [redis]...), so two sinks
+// of the same kind would write to the same place. Returns the first kind listed twice.
+pub fn duplicate_kind(sinks: &[crate::service::config::Sink]) -> Option<&str> {
+    sinks.iter().enumerate().find_map(|(i, sink)| {
+        sinks[..i]
+            .iter()
+            .any(|earlier| earlier.kind == sink.kind)
+            .then_some(sink.kind.as_str())
+    })
+}
+
+// Write every record to each of the wrapped datastores concurrently.
+// A failing sink does not stop the others, and its error is reported under the sink's name.
+// Host subsets apply to traffic and WebSocket sessions - auth info is written to every sink.
+pub struct Fanout {
+    sinks: Vec<Sink>,
+}
+
+#[async_trait]
+impl Datastore for Fanout {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .filter(|sink| sink.accepts_host(&traffic.host))
+                .map(|sink| async move {
+                    (
+                        &sink.name,
+                        sink.datastore
+                            .add_traffic(traffic)
+                            .await
+                            .map_err(|e| e.to_string()),
+                    )
+                }),
+        )
+        .await;
+        Self::collect_errors(results)
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        let results = join_all(self.sinks.iter().map(|sink| async move {
+            (
+                &sink.name,
+                sink.datastore
+                    .add_authinfo(auth)
+                    .await
+                    .map_err(|e| e.to_string()),
+            )
+        }))
+        .await;
+        Self::collect_errors(results)
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &crate::model::websocket::WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .filter(|sink| sink.accepts_host(&session.host))
+                .map(|sink| async move {
+                    (
+                        &sink.name,
+                        sink.datastore
+                            .add_websocket_session(session)
+                            .await
+                            .map_err(|e| e.to_string()),
+                    )
+                }),
+        )
+        .await;
+        Self::collect_errors(results)
+    }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let results = join_all(self.sinks.iter().map(|sink| async move {
+            let accepted: Vec<crate::Traffic> = traffic
+                .iter()
+                .filter(|t| sink.accepts_host(&t.host))
+                .cloned()
+                .collect();
+            if accepted.is_empty() {
+                return (&sink.name, Ok(()));
+            }
+            (
+                &sink.name,
+                sink.datastore
+                    .add_traffic_batch(&accepted)
+                    .await
+                    .map_err(|e| e.to_string()),
+            )
+        }))
+        .await;
+        Self::collect_errors(results)
+    }
+    async fn apply_retention(
+        &self,
+        policy: &crate::data::retention::Policy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let results = join_all(self.sinks.iter().map(|sink| async move {
+            (
+                &sink.name,
+                sink.datastore
+                    .apply_retention(policy)
+                    .await
+                    .map_err(|e| e.to_string()),
+            )
+        }))
+        .await;
+        let expired = results
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok())
+            .sum();
+        let results = results
+            .into_iter()
+            .map(|(name, result)| (name, result.map(|_| ())))
+            .collect();
+        Self::collect_errors(results).map(|()| expired)
+    }
+    // Reads come from the first sink that can answer them, in the order the sinks are listed.
+    async fn find_traffic(
+        &self,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        let mut errors = Vec::new();
+        for sink in &self.sinks {
+            match sink.datastore.find_traffic(query).await {
+                Ok(traffic) => return Ok(traffic),
+                Err(e) => errors.push((&sink.name, Err(e.to_string()))),
+            }
+        }
+        Self::collect_errors(errors).map(|()| Vec::new())
+    }
+    async fn distinct_traffic(
+        &self,
+        field: crate::data::query::Field,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut errors = Vec::new();
+        for sink in &self.sinks {
+            match sink.datastore.distinct_traffic(field, query).await {
+                Ok(values) => return Ok(values),
+                Err(e) => errors.push((&sink.name, Err(e.to_string()))),
+            }
+        }
+        Self::collect_errors(errors).map(|()| Vec::new())
+    }
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let results = join_all(self.sinks.iter().map(|sink| async move {
+            (
+                &sink.name,
+                sink.datastore.flush().await.map_err(|e| e.to_string()),
+            )
+        }))
+        .await;
+        Self::collect_errors(results)
+    }
+}
+
+impl Fanout {
+    pub fn new(sinks: Vec<Sink>) -> Self {
+        Self { sinks }
+    }
+
+    fn collect_errors(
+        results: Vec<(&String, Result<(), String>)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let errors: Vec<String> = results
+            .into_iter()
+            .filter_map(|(name, result)| result.err().map(|e| format!("[sink {}] {}", name, e)))
+            .collect();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("; ").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::service::config;
+    use crate::Traffic;
+    use std::sync::Arc;
+
+    // Fails every write, like a datastore that's down.
+    struct Down;
+
+    #[async_trait]
+    impl Datastore for Down {
+        async fn add_traffic(&self, _traffic: &Traffic) -> Result<(), Box<dyn std::error::Error>> {
+            Err("connection refused".into())
+        }
+        async fn add_authinfo(
+            &self,
+            _auth: &crate::AuthInfo,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Err("connection refused".into())
+        }
+    }
+
+    fn sink(kind: &str) -> config::Sink {
+        config::Sink {
+            name: None,
+            kind: kind.to_string(),
+            allow_list_hosts: Vec::new(),
+            deny_list_hosts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_accepts_host() {
+        let mut sink = Sink::new("team", Box::new(Memory::new(1)));
+        assert!(sink.accepts_host("anything.com"));
+
+        sink.allow_list_hosts = vec!["foobar.com".to_string()];
+        sink.deny_list_hosts = vec!["sso.foobar.com".to_string()];
+        assert!(sink.accepts_host("www.foobar.com"));
+        assert!(!sink.accepts_host("sso.foobar.com"));
+        assert!(!sink.accepts_host("evil.com"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_sink_does_not_stop_others() {
+        let archive = Arc::new(Memory::new(10));
+        let mut scoped = Sink::new("scoped", Box::new(Arc::new(Memory::new(10))));
+        scoped.allow_list_hosts = vec!["elsewhere.com".to_string()];
+        let fanout = Fanout::new(vec![
+            Sink::new("down", Box::new(Down)),
+            Sink::new("archive", Box::new(archive.clone())),
+            scoped,
+        ]);
+
+        let error = fanout
+            .add_traffic(&Traffic::fixture("foobar.com", "/"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "[sink down] connection refused");
+        assert_eq!(archive.len(), 1);
+
+        let mut also_down = Sink::new("also_down", Box::new(Down));
+        also_down.deny_list_hosts = vec!["foobar.com".to_string()];
+        let fanout = Fanout::new(vec![also_down, Sink::new("down", Box::new(Down))]);
+        let error = fanout
+            .add_traffic(&Traffic::fixture("foobar.com", "/"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "[sink down] connection refused");
+    }
+
+    #[test]
+    fn test_duplicate_kind() {
+        assert_eq!(duplicate_kind(&[sink("jsonl"), sink("mongo")]), None);
+        assert_eq!(
+            duplicate_kind(&[sink("mongo"), sink("jsonl"), sink("mongo")]),
+            Some("mongo")
+        );
+    }
+}
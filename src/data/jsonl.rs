@@ -0,0 +1,387 @@
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio_util::task::AbortOnDropHandle;
+
+use crate::data::retention::Policy;
+use crate::data::Datastore;
+
+// Append traffic as JSON Lines to rotating files, for recording without a database.
+pub struct JsonLines {
+    traffic_file: Arc<RotatingFile>,
+    auth_file: Arc<RotatingFile>,
+    websocket_file: Arc<RotatingFile>,
+    _rotation: Option<AbortOnDropHandle<()>>,
+}
+
+#[async_trait]
+impl Datastore for JsonLines {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.traffic_file.append(&traffic.get_json()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        match self.auth_file.append(&auth.get_json()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &crate::model::websocket::WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.websocket_file.append(&session.get_json()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    // Files can only be expired whole, so only max_age applies.
+    async fn apply_retention(&self, policy: &Policy) -> Result<u64, Box<dyn std::error::Error>> {
+        match policy.max_age {
+            Some(max_age) => Ok(self.traffic_file.expire(max_age).await?
+                + self.websocket_file.expire(max_age).await?),
+            None => Ok(0),
+        }
+    }
+}
+
+impl JsonLines {
+    pub async fn new(config: &crate::service::config::Config) -> Result<Self, std::io::Error> {
+        let jsonl = config.jsonl.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "[jsonl] must be configured to use the JSON Lines datastore.",
+            )
+        })?;
+        std::fs::create_dir_all(&jsonl.directory)?;
+        let max_age = jsonl.max_age_secs.map(Duration::from_secs);
+        let file = |prefix: &str| {
+            Arc::new(RotatingFile::new(
+                &jsonl.directory,
+                prefix,
+                jsonl.max_bytes,
+                max_age,
+                jsonl.gzip,
+            ))
+        };
+        let traffic_file = file(&config.db.traffic_collection_name);
+        let auth_file = file(&config.db.auth_collection_name);
+        let websocket_file = file(&config.db.websocket_collection_name);
+        let rotation = max_age.map(|max_age| {
+            spawn_rotation(
+                vec![
+                    traffic_file.clone(),
+                    auth_file.clone(),
+                    websocket_file.clone(),
+                ],
+                max_age,
+            )
+        });
+        Ok(Self {
+            traffic_file,
+            auth_file,
+            websocket_file,
+            _rotation: rotation,
+        })
+    }
+}
+
+// Rotation is otherwise checked on append, so a file nobody writes to would stay open forever.
+// Stops once the datastore is dropped.
+fn spawn_rotation(files: Vec<Arc<RotatingFile>>, max_age: Duration) -> AbortOnDropHandle<()> {
+    AbortOnDropHandle::new(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval((max_age / 4).max(Duration::from_secs(1)));
+        loop {
+            ticker.tick().await;
+            for file in &files {
+                if let Err(e) = file.rotate_idle().await {
+                    eprintln!("[ERROR] [src/data/jsonl.rs] [spawn_rotation]: {}", e);
+                }
+            }
+        }
+    }))
+}
+
+struct Segment {
+    file: tokio::fs::File,
+    path: PathBuf,
+    opened_at: SystemTime,
+    bytes: u64,
+}
+
+// A file named "{prefix}-{unix millis}.jsonl" that is closed and replaced once it grows past
+// max_bytes or gets older than max_age. Closed segments are optionally gzipped in the background.
+pub struct RotatingFile {
+    directory: PathBuf,
+    prefix: String,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    gzip: bool,
+    segment: Mutex<Option<Segment>>,
+}
+
+impl RotatingFile {
+    pub fn new(
+        directory: &str,
+        prefix: &str,
+        max_bytes: Option<u64>,
+        max_age: Option<Duration>,
+        gzip: bool,
+    ) -> Self {
+        Self {
+            directory: PathBuf::from(directory),
+            prefix: prefix.to_string(),
+            max_bytes,
+            max_age,
+            gzip,
+            segment: Mutex::new(None),
+        }
+    }
+
+    pub async fn append(&self, line: &str) -> Result<(), std::io::Error> {
+        let mut segment = self.segment.lock().await;
+        let line_bytes = line.len() as u64 + 1;
+
+        if let Some(current) = segment.as_ref() {
+            if self.should_rotate(current, line_bytes) {
+                let closed = segment.take().unwrap();
+                self.close(closed).await?;
+            }
+        }
+        if segment.is_none() {
+            *segment = Some(self.open().await?);
+        }
+
+        let current = segment.as_mut().unwrap();
+        current.file.write_all(line.as_bytes()).await?;
+        current.file.write_all(b"\n").await?;
+        current.file.flush().await?;
+        current.bytes += line_bytes;
+        Ok(())
+    }
+
+    // Close the open segment if it has outlived max_age. The next append opens a new one.
+    pub async fn rotate_idle(&self) -> Result<(), std::io::Error> {
+        let mut segment = self.segment.lock().await;
+        let expired = match (segment.as_ref(), self.max_age) {
+            (Some(current), Some(max_age)) => {
+                current.opened_at.elapsed().unwrap_or_default() >= max_age
+            }
+            _ => false,
+        };
+        if expired {
+            let closed = segment.take().unwrap();
+            self.close(closed).await?;
+        }
+        Ok(())
+    }
+
+    // Remove closed segments last written to more than max_age ago, returning how many went.
+    pub async fn expire(&self, max_age: Duration) -> Result<u64, std::io::Error> {
+        let segment = self.segment.lock().await;
+        let current = segment.as_ref().map(|segment| segment.path.clone());
+        let cutoff = crate::data::retention::cutoff(max_age);
+        let mut expired = 0;
+        let mut entries = tokio::fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name().into_string().unwrap_or_default();
+            let is_segment = name.starts_with(&format!("{}-", self.prefix))
+                && (name.ends_with(".jsonl") || name.ends_with(".jsonl.gz"));
+            if !is_segment || Some(&path) == current.as_ref() {
+                continue;
+            }
+            if entry.metadata().await?.modified()? < cutoff {
+                tokio::fs::remove_file(&path).await?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+
+    fn should_rotate(&self, segment: &Segment, incoming: u64) -> bool {
+        if let Some(max_bytes) = self.max_bytes {
+            // A single line larger than max_bytes still gets a segment to itself.
+            if segment.bytes > 0 && segment.bytes + incoming > max_bytes {
+                return true;
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            if segment.opened_at.elapsed().unwrap_or_default() >= max_age {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn open(&self) -> Result<Segment, std::io::Error> {
+        let opened_at = SystemTime::now();
+        let mut millis = opened_at.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut path = self.segment_path(millis);
+        // Never reopen a segment that was rotated within the same millisecond.
+        while path.exists() || path.with_extension("jsonl.gz").exists() {
+            millis += 1;
+            path = self.segment_path(millis);
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        Ok(Segment {
+            file,
+            path,
+            opened_at,
+            bytes: 0,
+        })
+    }
+
+    async fn close(&self, mut segment: Segment) -> Result<(), std::io::Error> {
+        segment.file.flush().await?;
+        drop(segment.file);
+        if self.gzip {
+            let path = segment.path;
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = gzip_segment(&path) {
+                    eprintln!(
+                        "[ERROR] [src/data/jsonl.rs] [close]: (gzip {:?} failed) {}",
+                        path, e
+                    );
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn segment_path(&self, millis: u128) -> PathBuf {
+        self.directory
+            .join(format!("{}-{}.jsonl", self.prefix, millis))
+    }
+}
+
+// Compress "{segment}.jsonl" into "{segment}.jsonl.gz" and remove the original.
+fn gzip_segment(path: &Path) -> Result<(), std::io::Error> {
+    let gz_path = path.with_extension("jsonl.gz");
+    let mut input = std::fs::File::open(path)?;
+    let output = std::fs::File::create(&gz_path)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("ohm-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn list_segments(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_rotate_on_size() -> Result<(), std::io::Error> {
+        let directory = test_directory("jsonl-size");
+        let file = RotatingFile::new(
+            directory.to_str().unwrap(),
+            "traffic",
+            Some(16),
+            None,
+            false,
+        );
+        file.append("{\"a\":\"0123456\"}").await?; // 16 bytes with the newline.
+        file.append("{\"b\":1}").await?;
+        file.append("{\"c\":2}").await?;
+
+        let segments = list_segments(&directory);
+        assert_eq!(segments.len(), 2);
+        assert!(segments.iter().all(|name| name.starts_with("traffic-")));
+        let contents = std::fs::read_to_string(directory.join(&segments[1]))?;
+        assert_eq!(contents, "{\"b\":1}\n{\"c\":2}\n");
+
+        std::fs::remove_dir_all(&directory)
+    }
+
+    #[tokio::test]
+    async fn test_rotate_idle() -> Result<(), std::io::Error> {
+        let directory = test_directory("jsonl-idle");
+        let file = RotatingFile::new(
+            directory.to_str().unwrap(),
+            "traffic",
+            None,
+            Some(Duration::from_millis(50)),
+            true,
+        );
+        file.append("{\"a\":0}").await?;
+        file.rotate_idle().await?;
+        assert!(file.segment.lock().await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        file.rotate_idle().await?;
+        assert!(file.segment.lock().await.is_none());
+        for _ in 0..100 {
+            if list_segments(&directory)[0].ends_with(".jsonl.gz") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(list_segments(&directory)[0].ends_with(".jsonl.gz"));
+
+        std::fs::remove_dir_all(&directory)
+    }
+
+    #[test]
+    fn test_gzip_segment() -> Result<(), std::io::Error> {
+        let directory = test_directory("jsonl-gzip");
+        let path = directory.join("traffic-0.jsonl");
+        std::fs::write(&path, "{\"a\":0}\n")?;
+        gzip_segment(&path)?;
+
+        assert_eq!(list_segments(&directory), vec!["traffic-0.jsonl.gz"]);
+        let mut contents = String::new();
+        let mut gz =
+            flate2::read::GzDecoder::new(std::fs::File::open(path.with_extension("jsonl.gz"))?);
+        std::io::Read::read_to_string(&mut gz, &mut contents)?;
+        assert_eq!(contents, "{\"a\":0}\n");
+
+        std::fs::remove_dir_all(&directory)
+    }
+
+    #[tokio::test]
+    async fn test_expire_keeps_open_segment() -> Result<(), std::io::Error> {
+        let directory = test_directory("jsonl-expire");
+        std::fs::write(directory.join("traffic-1.jsonl.gz"), "")?;
+        std::fs::write(directory.join("authinfo-1.jsonl"), "")?;
+        let file = RotatingFile::new(directory.to_str().unwrap(), "traffic", None, None, false);
+        file.append("{\"a\":0}").await?;
+
+        assert_eq!(file.expire(Duration::ZERO).await?, 1);
+        let segments = list_segments(&directory);
+        assert_eq!(segments.len(), 2);
+        assert!(segments.contains(&"authinfo-1.jsonl".to_string()));
+
+        std::fs::remove_dir_all(&directory)
+    }
+}
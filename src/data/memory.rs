@@ -0,0 +1,337 @@
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::RwLock;
+
+use crate::data::query::{Field, TrafficQuery};
+use crate::data::retention::{cutoff, Policy, CREDENTIAL_HEADERS};
+use crate::data::Datastore;
+use crate::model::auth::AuthInfo;
+use crate::model::traffic::Traffic;
+use crate::model::websocket::WebSocketSession;
+
+// How many entries kind = "memory" keeps without a [memory] section.
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+// Keep the most recent traffic in a bounded ring buffer - nothing touches the disk.
+// Useful for tests that need to see what was stored, and as a cache of recent traffic.
+// Queries scan the whole buffer.
+pub struct Memory {
+    traffic: RwLock<VecDeque<Traffic>>,
+    auth: RwLock<Vec<AuthInfo>>,
+    websocket_sessions: RwLock<VecDeque<WebSocketSession>>,
+    capacity: usize,
+}
+
+#[async_trait]
+impl Datastore for Memory {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.push(std::slice::from_ref(traffic));
+        Ok(())
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        // Upserted like the database stores - each auth flow is kept once.
+        let mut stored = self.auth.write().unwrap();
+        if !stored.contains(auth) {
+            stored.push(auth.clone());
+        }
+        Ok(())
+    }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.push(traffic);
+        Ok(())
+    }
+    async fn apply_retention(&self, policy: &Policy) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(self.expire(policy))
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stored = self.websocket_sessions.write().unwrap();
+        stored.push_back(session.clone());
+        if stored.len() > self.capacity {
+            stored.pop_front();
+        }
+        Ok(())
+    }
+    async fn find_traffic(
+        &self,
+        query: &TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        let matches = query.matcher()?;
+        let traffic = self
+            .traffic
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|traffic| matches(traffic))
+            .cloned()
+            .collect();
+        Ok(query.page(traffic))
+    }
+    async fn distinct_traffic(
+        &self,
+        field: Field,
+        query: &TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let matches = query.matcher()?;
+        let mut values: Vec<String> = self
+            .traffic
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|traffic| matches(traffic))
+            .map(|traffic| field.value(traffic))
+            .collect();
+        values.sort();
+        values.dedup();
+        Ok(values)
+    }
+}
+
+impl Memory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            traffic: RwLock::new(VecDeque::with_capacity(capacity)),
+            auth: RwLock::new(Vec::new()),
+            websocket_sessions: RwLock::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    // The last `count` entries, oldest first.
+    pub fn recent_traffic(&self, count: usize) -> Vec<Traffic> {
+        let traffic = self.traffic.read().unwrap();
+        traffic
+            .iter()
+            .skip(traffic.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+
+    pub fn auth_info(&self) -> Vec<AuthInfo> {
+        self.auth.read().unwrap().clone()
+    }
+
+    // Oldest first, bounded by the same capacity as traffic.
+    pub fn websocket_sessions(&self) -> Vec<WebSocketSession> {
+        self.websocket_sessions
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.traffic.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.traffic.write().unwrap().clear();
+        self.auth.write().unwrap().clear();
+        self.websocket_sessions.write().unwrap().clear();
+    }
+
+    // Append traffic, evicting the oldest entries once the buffer is at capacity.
+    // Push before evicting, so a capacity of 0 keeps nothing.
+    fn push(&self, traffic: &[Traffic]) {
+        let mut stored = self.traffic.write().unwrap();
+        for t in traffic {
+            stored.push_back(t.clone());
+            if stored.len() > self.capacity {
+                stored.pop_front();
+            }
+        }
+    }
+
+    // Traffic without a capture time is never old enough to expire.
+    fn expire(&self, policy: &Policy) -> u64 {
+        let older_than = |traffic: &Traffic, max_age| {
+            traffic
+                .captured_at
+                .is_some_and(|captured_at| captured_at.to_system_time() < cutoff(max_age))
+        };
+        let mut stored = self.traffic.write().unwrap();
+        let before = stored.len();
+        stored.retain(|traffic| {
+            let credentialed = CREDENTIAL_HEADERS
+                .iter()
+                .any(|header| traffic.request_headers.contains_key(*header));
+            !(policy
+                .max_age
+                .is_some_and(|max_age| older_than(traffic, max_age))
+                || policy.hosts.iter().any(|host| {
+                    traffic.host.contains(host.host.as_str()) && older_than(traffic, host.max_age)
+                })
+                || (credentialed
+                    && policy
+                        .credentialed_max_age
+                        .is_some_and(|max_age| older_than(traffic, max_age))))
+        });
+        if let Some(max_documents) = policy.max_documents {
+            let excess = stored.len().saturating_sub(max_documents as usize);
+            stored.drain(..excess);
+        }
+        let mut expired = before - stored.len();
+
+        // Sessions go by the age limits, from when they started.
+        let started_before = |session: &WebSocketSession, max_age| {
+            session
+                .started_at
+                .is_some_and(|started_at| started_at.to_system_time() < cutoff(max_age))
+        };
+        let mut sessions = self.websocket_sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|session| {
+            !(policy
+                .max_age
+                .is_some_and(|max_age| started_before(session, max_age))
+                || policy.hosts.iter().any(|host| {
+                    session.host.contains(host.host.as_str())
+                        && started_before(session, host.max_age)
+                }))
+        });
+        expired += before - sessions.len();
+        expired as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::query::Sort;
+    use mongodb::bson::DateTime;
+
+    fn paths(traffic: Vec<Traffic>) -> Vec<String> {
+        traffic.into_iter().map(|t| t.path).collect()
+    }
+
+    #[tokio::test]
+    async fn test_memory_query() -> Result<(), Box<dyn std::error::Error>> {
+        let memory = Memory::new(10);
+        memory
+            .add_traffic_batch(&[
+                Traffic::fixture("foobar.com", "/api/users"),
+                Traffic {
+                    status: 302,
+                    ..Traffic::fixture("sso.foobar.com", "/oauth/token")
+                },
+                Traffic {
+                    status: 404,
+                    ..Traffic::fixture("evil.com", "/api/users")
+                },
+            ])
+            .await?;
+
+        let query = TrafficQuery {
+            host: Some("foobar.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            paths(memory.find_traffic(&query).await?),
+            vec!["/api/users", "/oauth/token"]
+        );
+        let query = TrafficQuery {
+            path: Some("^/api/".to_string()),
+            status: Some(400..=499),
+            ..Default::default()
+        };
+        assert_eq!(memory.find_traffic(&query).await?[0].host, "evil.com");
+        let query = TrafficQuery {
+            sort: Some(Sort {
+                field: Field::Status,
+                descending: true,
+            }),
+            skip: 1,
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            paths(memory.find_traffic(&query).await?),
+            vec!["/oauth/token"]
+        );
+        assert_eq!(
+            memory
+                .distinct_traffic(Field::Path, &TrafficQuery::default())
+                .await?,
+            vec!["/api/users", "/oauth/token"]
+        );
+        assert_eq!(paths(memory.recent_traffic(1)), vec!["/api/users"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_evicts_oldest() -> Result<(), Box<dyn std::error::Error>> {
+        let memory = Memory::new(2);
+        for path in ["/1", "/2", "/3"] {
+            memory
+                .add_traffic(&Traffic {
+                    captured_at: Some(DateTime::now()),
+                    ..Traffic::fixture("foobar.com", path)
+                })
+                .await?;
+        }
+        assert_eq!(
+            paths(memory.find_traffic(&TrafficQuery::default()).await?),
+            vec!["/2", "/3"]
+        );
+
+        let old = Traffic {
+            captured_at: Some(DateTime::from_millis(0)),
+            ..Traffic::fixture("foobar.com", "/old")
+        };
+        memory.add_traffic(&old).await?;
+        let policy = Policy {
+            max_age: Some(std::time::Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(memory.apply_retention(&policy).await?, 1);
+        assert_eq!(paths(memory.recent_traffic(10)), vec!["/3"]);
+
+        let memory = Memory::new(0);
+        memory
+            .add_traffic(&Traffic::fixture("foobar.com", "/1"))
+            .await?;
+        assert!(memory.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_expires_sessions() -> Result<(), Box<dyn std::error::Error>> {
+        let memory = Memory::new(10);
+        for (path, started_at) in [
+            ("/old", DateTime::from_millis(0)),
+            ("/new", DateTime::now()),
+        ] {
+            let mut session = WebSocketSession::new(&Traffic {
+                status: 101,
+                ..Traffic::fixture("foobar.com", path)
+            });
+            session.started_at = Some(started_at);
+            memory.add_websocket_session(&session).await?;
+        }
+        let policy = Policy {
+            hosts: vec![crate::data::retention::HostPolicy {
+                host: "foobar.com".to_string(),
+                max_age: std::time::Duration::from_secs(60),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(memory.apply_retention(&policy).await?, 1);
+        let sessions = memory.websocket_sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].path, "/new");
+        Ok(())
+    }
+}
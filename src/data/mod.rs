@@ -1,11 +1,276 @@
 use async_trait::async_trait;
+use std::sync::Arc;
 
+use crate::service::config::Config;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub mod batch;
+pub mod bodies;
+pub mod encrypt;
+pub mod fanout;
+pub mod jsonl;
+pub mod memory;
 pub mod mongo;
+pub mod postgres;
+pub mod query;
+pub mod redis;
+pub mod retention;
+pub mod spool;
+pub mod sqlite;
 
 // https://smallcultfollowing.com/babysteps/blog/2019/10/26/async-fn-in-traits-are-hard/
 #[async_trait]
-pub trait Datastore {
+pub trait Datastore: Send + Sync {
     async fn add_traffic(&self, traffic: &crate::Traffic)
         -> Result<(), Box<dyn std::error::Error>>;
     async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Stores that can write many documents at once should override this.
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for t in traffic {
+            self.add_traffic(t).await?;
+        }
+        Ok(())
+    }
+
+    // Remove traffic and WebSocket sessions the retention policy has expired, returning how many
+    // entries were removed.
+    // Stores that can't expire anything keep everything.
+    async fn apply_retention(
+        &self,
+        _policy: &retention::Policy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(0)
+    }
+
+    // Read back the traffic matching a query, for tooling that shouldn't care which store it's on.
+    // Write-only stores (JSONL files, Redis streams) can't answer and return an error.
+    async fn find_traffic(
+        &self,
+        _query: &query::TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        Err(UNSUPPORTED_QUERY.into())
+    }
+
+    // The distinct values of a field across the traffic matching a query.
+    async fn distinct_traffic(
+        &self,
+        _field: query::Field,
+        _query: &query::TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Err(UNSUPPORTED_QUERY.into())
+    }
+
+    // Store the frames of a WebSocket connection once it has closed.
+    async fn add_websocket_session(
+        &self,
+        _session: &crate::model::websocket::WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err(UNSUPPORTED_WEBSOCKETS.into())
+    }
+
+    // Write out anything buffered in memory, e.g. before the process exits.
+    // Stores that write straight through have nothing to do.
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+pub const UNSUPPORTED_QUERY: &str = "This datastore does not support queries.";
+pub const UNSUPPORTED_WEBSOCKETS: &str = "This datastore does not store WebSocket sessions.";
+
+// Lets one datastore be shared, e.g. a spool that a batcher also spills into.
+#[async_trait]
+impl<T: Datastore + ?Sized> Datastore for Arc<T> {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).add_traffic(traffic).await
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).add_authinfo(auth).await
+    }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).add_traffic_batch(traffic).await
+    }
+    async fn apply_retention(
+        &self,
+        policy: &retention::Policy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        (**self).apply_retention(policy).await
+    }
+    async fn find_traffic(
+        &self,
+        query: &query::TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        (**self).find_traffic(query).await
+    }
+    async fn distinct_traffic(
+        &self,
+        field: query::Field,
+        query: &query::TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        (**self).distinct_traffic(field, query).await
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &crate::model::websocket::WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).add_websocket_session(session).await
+    }
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).flush().await
+    }
+}
+
+// Build the datastore selected by the config.
+// [[sinks]] fans traffic out to several datastores, each with its own host subset.
+// Otherwise [db] kind selects the store, and a [redis] section publishes alongside it.
+pub async fn new_datastore(config: &Config) -> Result<Box<dyn Datastore>, Error> {
+    let encryptor = match &config.encryption {
+        Some(encryption) => {
+            let cipher = encrypt::FieldCipher::from_key_file(&encryption.key_path)
+                .map_err(|e| format!("[encryption] key_path must hold a 32 byte key: {}", e))?;
+            Some(Arc::new(encrypt::Encryptor::new(
+                cipher,
+                &encryption.headers,
+                encryption.bodies,
+            )))
+        }
+        None => None,
+    };
+    new_sinks(config, encryptor.as_ref()).await
+}
+
+async fn new_sinks(
+    config: &Config,
+    encryptor: Option<&Arc<encrypt::Encryptor>>,
+) -> Result<Box<dyn Datastore>, Error> {
+    if !config.sinks.is_empty() {
+        if let Some(kind) = fanout::duplicate_kind(&config.sinks) {
+            return Err(format!(
+                "Only one of the [[sinks]] can be of kind {:?} - they would share its config section.",
+                kind
+            )
+            .into());
+        }
+        let mut sinks = Vec::<fanout::Sink>::new();
+        for sink_config in &config.sinks {
+            let name = sink_config.name.as_ref().unwrap_or(&sink_config.kind);
+            let store = with_write_path(
+                config,
+                name,
+                new_store(config, &sink_config.kind).await?,
+                encryptor,
+            )
+            .await?;
+            let mut sink = fanout::Sink::new(name, store);
+            sink.allow_list_hosts = sink_config.allow_list_hosts.clone();
+            sink.deny_list_hosts = sink_config.deny_list_hosts.clone();
+            sinks.push(sink);
+        }
+        return Ok(Box::new(fanout::Fanout::new(sinks)));
+    }
+
+    let kind = &config.db.kind;
+    let primary = with_write_path(config, kind, new_store(config, kind).await?, encryptor).await?;
+    match (&config.redis, kind.as_str()) {
+        (Some(_), kind) if kind != "redis" => {
+            let redis = new_store(config, "redis").await?;
+            Ok(Box::new(fanout::Fanout::new(vec![
+                fanout::Sink::new(kind, primary),
+                fanout::Sink::new(
+                    "redis",
+                    with_write_path(config, "redis", redis, encryptor).await?,
+                ),
+            ])))
+        }
+        _ => Ok(primary),
+    }
+}
+
+// Wrap a store in the configured body deduplication, encryption, batching and spool stages.
+// Each store gets its own spool directory so a replay never duplicates into stores that succeeded.
+// Bodies are moved out before encryption, so deduplication sees the plaintext, and anything
+// queued or spooled is already sealed.
+async fn with_write_path(
+    config: &Config,
+    name: &str,
+    datastore: Box<dyn Datastore>,
+    encryptor: Option<&Arc<encrypt::Encryptor>>,
+) -> Result<Box<dyn Datastore>, Error> {
+    let (datastore, spool): (Box<dyn Datastore>, _) = match &config.spool {
+        Some(spool) => {
+            let spool = Arc::new(spool::Spool::new(
+                datastore,
+                &format!("{}/{}", spool.directory, name),
+                spool.max_bytes,
+                std::time::Duration::from_millis(spool.retry_initial_ms),
+                std::time::Duration::from_millis(spool.retry_max_ms),
+                spool.max_attempts,
+            ));
+            (Box::new(spool.clone()), Some(spool))
+        }
+        None => (datastore, None),
+    };
+    let datastore: Box<dyn Datastore> = match &config.batch {
+        Some(batch) => Box::new(batch::Batcher::new(
+            datastore,
+            batch.capacity,
+            batch.max_batch_size,
+            std::time::Duration::from_millis(batch.window_ms),
+            batch::Backpressure::from_config(&batch.backpressure)?,
+            spool,
+        )?),
+        None => datastore,
+    };
+    let datastore: Box<dyn Datastore> = match encryptor {
+        Some(encryptor) => Box::new(encrypt::Encrypted::new(datastore, encryptor.clone())),
+        None => datastore,
+    };
+    match &config.bodies {
+        Some(bodies) => {
+            let mut body_store: Box<dyn bodies::BodyStore> = match bodies.store.as_str() {
+                "directory" => Box::new(bodies::BlobDirectory::new(&bodies.directory)),
+                "mongo" => {
+                    Box::new(mongo::MongoBodies::new(&config.db, &bodies.collection_name).await?)
+                }
+                _ => return Err(format!("Unknown [bodies] store: {}", bodies.store).into()),
+            };
+            if let Some(encryptor) = encryptor.filter(|encryptor| encryptor.encrypts_bodies()) {
+                body_store = Box::new(encrypt::EncryptedBodies::new(body_store, encryptor.clone()));
+            }
+            Ok(Box::new(bodies::ContentAddressed::new(
+                datastore,
+                body_store,
+                bodies.threshold_bytes,
+            )))
+        }
+        None => Ok(datastore),
+    }
+}
+
+async fn new_store(config: &Config, kind: &str) -> Result<Box<dyn Datastore>, Error> {
+    Ok(match kind {
+        "mongo" => Box::new(mongo::Mongo::new(config).await?),
+        "postgres" => Box::new(postgres::Postgres::new(config).await?),
+        "jsonl" => Box::new(jsonl::JsonLines::new(config).await?),
+        "redis" => Box::new(redis::RedisStreams::new(config).await?),
+        "sqlite" => Box::new(sqlite::Sqlite::new(config).await?),
+        "memory" => Box::new(memory::Memory::new(
+            config
+                .memory
+                .as_ref()
+                .map_or(memory::DEFAULT_CAPACITY, |memory| memory.capacity),
+        )),
+        _ => return Err(format!("Unknown datastore kind: {}", kind).into()),
+    })
 }
@@ -1,16 +1,31 @@
 use async_trait::async_trait;
-use mongodb::{bson::doc, options::ClientOptions, Client};
+use futures::TryStreamExt;
+use mongodb::bson::Bson;
+use mongodb::bson::{doc, spec::BinarySubtype, Binary, DateTime, Document};
+use mongodb::options::{ClientOptions, FindOneOptions, FindOptions, IndexOptions};
+use mongodb::{Client, IndexModel};
+use std::time::Duration;
 
+use crate::data::bodies::BodyStore;
+use crate::data::query::{Field, TrafficQuery};
+use crate::data::retention::{cutoff, Policy, CREDENTIAL_HEADERS};
 use crate::data::Datastore;
 use crate::model::auth::AuthInfo;
 use crate::model::traffic::Traffic;
+use crate::model::websocket::WebSocketSession;
+use crate::service::config::Config;
 
 const APP_NAME: &str = "ohm";
+const TTL_INDEX_NAME: &str = "captured_at_ttl";
+const TEXT_INDEX_NAME: &str = "body_text";
+const AUTH_INDEX_NAME: &str = "authinfo_upsert_key";
 
 // Manage and store all datastore interactions.
 pub struct Mongo {
+    database: mongodb::Database,
     traffic_collection: mongodb::Collection<Traffic>,
     auth_collection: mongodb::Collection<AuthInfo>,
+    websocket_collection: mongodb::Collection<WebSocketSession>,
 }
 
 #[async_trait]
@@ -30,23 +45,134 @@ impl Datastore for Mongo {
             Err(e) => Err(Box::new(e)),
         }
     }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_traffic_many(traffic).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.websocket_collection.insert_one(session, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn apply_retention(&self, policy: &Policy) -> Result<u64, Box<dyn std::error::Error>> {
+        let expired = self.expire_traffic(policy).await?;
+        Ok(expired + self.expire_websocket_sessions(policy).await?)
+    }
+    async fn find_traffic(
+        &self,
+        query: &TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        match self.query_traffic(query).await {
+            Ok(traffic) => Ok(traffic),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn distinct_traffic(
+        &self,
+        field: Field,
+        query: &TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        match self.query_distinct(field, query).await {
+            Ok(values) => Ok(values),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
 }
 
 impl Mongo {
-    pub async fn new() -> Self {
-        let con = Self::get_connection().await.unwrap();
-        let database = Self::get_database(&con).await.unwrap();
-        let traffic_collection = Self::get_traffic_collection(&database).await.unwrap();
-        let auth_collection = Self::get_auth_collection(&database).await.unwrap();
-        Self {
+    pub async fn new(config: &Config) -> Result<Self, mongodb::error::Error> {
+        let db = &config.db;
+        let con = Self::get_connection(&db.db_url).await?;
+        let database = Self::get_database(&con, &db.db_name).await?;
+        let traffic_collection =
+            Self::get_traffic_collection(&database, &db.traffic_collection_name).await?;
+        let auth_collection =
+            Self::get_auth_collection(&database, &db.auth_collection_name).await?;
+        let websocket_collection =
+            database.collection::<WebSocketSession>(&db.websocket_collection_name);
+        let mongo = Self {
+            database,
             traffic_collection,
             auth_collection,
-        }
+            websocket_collection,
+        };
+        mongo.ensure_indexes(config).await;
+        Ok(mongo)
     }
 
-    async fn get_connection() -> Result<mongodb::Client, mongodb::error::Error> {
-        let db_url = &crate::CONFIG.get().unwrap().db.db_url;
+    // Indexes for the common traffic queries, plus the key insert_auth upserts on.
+    // A failure here is reported but doesn't stop Ohm - queries are just slower without them.
+    async fn ensure_indexes(&self, config: &Config) {
+        let mut traffic_indexes = Vec::<IndexModel>::new();
+        // The retention TTL index on captured_at already serves captured_at queries.
+        let has_ttl_index = config
+            .retention
+            .as_ref()
+            .is_some_and(|retention| retention.max_age_secs.is_some());
+        for field in &config.db.indexes {
+            if field == "captured_at" && has_ttl_index {
+                continue;
+            }
+            let mut keys = Document::new();
+            keys.insert(field, 1);
+            traffic_indexes.push(IndexModel::builder().keys(keys).build());
+        }
+        if !config.db.text_index.is_empty() {
+            let mut keys = Document::new();
+            for field in &config.db.text_index {
+                keys.insert(field, "text");
+            }
+            let options = IndexOptions::builder()
+                .name(Some(TEXT_INDEX_NAME.to_string()))
+                .build();
+            traffic_indexes.push(IndexModel::builder().keys(keys).options(options).build());
+        }
+        if !traffic_indexes.is_empty() {
+            if let Err(e) = self
+                .traffic_collection
+                .create_indexes(traffic_indexes, None)
+                .await
+            {
+                eprintln!(
+                    "[ERROR] [src/data/mongo.rs] [ensure_indexes]: (traffic indexes) {}",
+                    e
+                );
+            }
+        }
 
+        let auth_index = IndexModel::builder()
+            .keys(doc! {
+                "issuer": 1,
+                "grant_type": 1,
+                "client_id": 1,
+                "redirect_url": 1,
+                "scope": 1,
+            })
+            .options(
+                IndexOptions::builder()
+                    .name(Some(AUTH_INDEX_NAME.to_string()))
+                    .unique(Some(true))
+                    .build(),
+            )
+            .build();
+        if let Err(e) = self.auth_collection.create_index(auth_index, None).await {
+            eprintln!(
+                "[ERROR] [src/data/mongo.rs] [ensure_indexes]: (authinfo index) {}",
+                e
+            );
+        }
+    }
+
+    async fn get_connection(db_url: &str) -> Result<mongodb::Client, mongodb::error::Error> {
         // Parse a connection string into an options struct.
         let mut client_options = ClientOptions::parse(db_url).await?;
         client_options.app_name = Some(APP_NAME.to_string());
@@ -57,23 +183,22 @@ impl Mongo {
 
     async fn get_database(
         client: &mongodb::Client,
+        db_name: &str,
     ) -> Result<mongodb::Database, mongodb::error::Error> {
-        let db_name = &crate::CONFIG.get().unwrap().db.db_name;
-        let db = Some(client.database(db_name));
-        Ok(db.unwrap())
+        Ok(client.database(db_name))
     }
 
     async fn get_traffic_collection(
         db: &mongodb::Database,
+        collection_name: &str,
     ) -> Result<mongodb::Collection<Traffic>, mongodb::error::Error> {
-        let collection_name = &crate::CONFIG.get().unwrap().db.traffic_collection_name;
         Ok(db.collection::<Traffic>(collection_name))
     }
 
     async fn get_auth_collection(
         db: &mongodb::Database,
+        collection_name: &str,
     ) -> Result<mongodb::Collection<AuthInfo>, mongodb::error::Error> {
-        let collection_name = &crate::CONFIG.get().unwrap().db.auth_collection_name;
         Ok(db.collection::<AuthInfo>(collection_name))
     }
 
@@ -85,6 +210,227 @@ impl Mongo {
         Ok(())
     }
 
+    pub async fn insert_traffic_many(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), mongodb::error::Error> {
+        self.traffic_collection.insert_many(traffic, None).await?;
+        Ok(())
+    }
+
+    // max_age is left to a TTL index on captured_at, everything else is a scheduled delete.
+    // The TTL index covers every host, so a host's max_age can only expire its traffic sooner.
+    pub async fn expire_traffic(&self, policy: &Policy) -> Result<u64, mongodb::error::Error> {
+        let mut expired = 0;
+        if let Some(max_age) = policy.max_age {
+            self.ensure_ttl_index(max_age).await?;
+        }
+        for host_policy in &policy.hosts {
+            let filter = doc! {
+                "host": { "$regex": regex::escape(&host_policy.host) },
+                "captured_at": { "$lt": DateTime::from_system_time(cutoff(host_policy.max_age)) },
+            };
+            expired += self
+                .traffic_collection
+                .delete_many(filter, None)
+                .await?
+                .deleted_count;
+        }
+        if let Some(credentialed_max_age) = policy.credentialed_max_age {
+            let mut credentialed = Vec::<Document>::new();
+            for header in CREDENTIAL_HEADERS {
+                let mut exists = Document::new();
+                exists.insert(
+                    format!("request_headers.{}", header),
+                    doc! { "$exists": true },
+                );
+                credentialed.push(exists);
+            }
+            let filter = doc! {
+                "$or": credentialed,
+                "captured_at": { "$lt": DateTime::from_system_time(cutoff(credentialed_max_age)) },
+            };
+            expired += self
+                .traffic_collection
+                .delete_many(filter, None)
+                .await?
+                .deleted_count;
+        }
+        if let Some(max_documents) = policy.max_documents {
+            // Everything captured at or before the newest document past the limit goes.
+            let options = FindOneOptions::builder()
+                .sort(doc! { "captured_at": -1 })
+                .skip(max_documents)
+                .projection(doc! { "captured_at": 1 })
+                .build();
+            let oldest_kept = self
+                .traffic_collection
+                .clone_with_type::<Document>()
+                .find_one(None, options)
+                .await?;
+            if let Some(Ok(captured_at)) =
+                oldest_kept.as_ref().map(|d| d.get_datetime("captured_at"))
+            {
+                let filter = doc! { "captured_at": { "$lte": captured_at } };
+                expired += self
+                    .traffic_collection
+                    .delete_many(filter, None)
+                    .await?
+                    .deleted_count;
+            }
+        }
+        Ok(expired)
+    }
+
+    // The filter document for a query. body_text relies on the text index from ensure_indexes.
+    fn traffic_filter(query: &TrafficQuery) -> Document {
+        let mut filter = Document::new();
+        if let Some(host) = &query.host {
+            filter.insert("host", doc! { "$regex": regex::escape(host) });
+        }
+        if let Some(method) = &query.method {
+            let method = format!("^{}$", regex::escape(method));
+            filter.insert("method", doc! { "$regex": method, "$options": "i" });
+        }
+        if let Some(status) = &query.status {
+            let (start, end) = (*status.start() as i32, *status.end() as i32);
+            filter.insert("status", doc! { "$gte": start, "$lte": end });
+        }
+        if let Some(path) = &query.path {
+            filter.insert("path", doc! { "$regex": path });
+        }
+        let mut captured_at = Document::new();
+        if let Some(after) = query.captured_after {
+            captured_at.insert("$gte", after);
+        }
+        if let Some(before) = query.captured_before {
+            captured_at.insert("$lt", before);
+        }
+        if !captured_at.is_empty() {
+            filter.insert("captured_at", captured_at);
+        }
+        if let Some(text) = &query.body_text {
+            filter.insert("$text", doc! { "$search": text });
+        }
+        if let Some(user) = &query.proxy_user {
+            filter.insert("proxy_user", user);
+        }
+        filter
+    }
+
+    pub async fn query_traffic(
+        &self,
+        query: &TrafficQuery,
+    ) -> Result<Vec<Traffic>, mongodb::error::Error> {
+        let sort = query.sort.map(|sort| {
+            let mut order = Document::new();
+            order.insert(sort.field.name(), if sort.descending { -1 } else { 1 });
+            order
+        });
+        let options = FindOptions::builder()
+            .sort(sort)
+            .skip(query.skip)
+            .limit(query.limit.map(|limit| limit as i64))
+            .build();
+        self.traffic_collection
+            .find(Self::traffic_filter(query), options)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub async fn query_distinct(
+        &self,
+        field: Field,
+        query: &TrafficQuery,
+    ) -> Result<Vec<String>, mongodb::error::Error> {
+        let values = self
+            .traffic_collection
+            .distinct(field.name(), Self::traffic_filter(query), None)
+            .await?;
+        Ok(values
+            .into_iter()
+            .map(|value| match value {
+                Bson::String(value) => value,
+                Bson::Int32(value) => value.to_string(),
+                Bson::DateTime(value) => value.to_string(),
+                value => value.to_string(),
+            })
+            .collect())
+    }
+
+    // Sessions go by the age limits, from when they started.
+    pub async fn expire_websocket_sessions(
+        &self,
+        policy: &Policy,
+    ) -> Result<u64, mongodb::error::Error> {
+        let mut filters = Vec::new();
+        if let Some(max_age) = policy.max_age {
+            filters.push(doc! {
+                "started_at": { "$lt": DateTime::from_system_time(cutoff(max_age)) },
+            });
+        }
+        for host_policy in &policy.hosts {
+            filters.push(doc! {
+                "host": { "$regex": regex::escape(&host_policy.host) },
+                "started_at": { "$lt": DateTime::from_system_time(cutoff(host_policy.max_age)) },
+            });
+        }
+        let mut expired = 0;
+        for filter in filters {
+            expired += self
+                .websocket_collection
+                .delete_many(filter, None)
+                .await?
+                .deleted_count;
+        }
+        Ok(expired)
+    }
+
+    // An index on captured_at alone may already exist - the TTL index with an older max_age, or a
+    // plain one from [db] indexes built before retention was configured. Mongo allows only one
+    // index per key pattern, so an existing one is converted in place with collMod. Turning a
+    // plain index into a TTL index that way needs MongoDB 5.1 or later.
+    async fn ensure_ttl_index(&self, max_age: Duration) -> Result<(), mongodb::error::Error> {
+        let mut indexes = self.traffic_collection.list_indexes(None).await?;
+        let mut existing = None;
+        while let Some(index) = indexes.try_next().await? {
+            if index.keys == doc! { "captured_at": 1 } {
+                existing = index.options.and_then(|options| options.name);
+                break;
+            }
+        }
+        match existing {
+            Some(name) => {
+                self.database
+                    .run_command(
+                        doc! {
+                            "collMod": self.traffic_collection.name(),
+                            "index": {
+                                "name": name,
+                                "expireAfterSeconds": max_age.as_secs() as i64,
+                            },
+                        },
+                        None,
+                    )
+                    .await?;
+            }
+            None => {
+                let index = IndexModel::builder()
+                    .keys(doc! { "captured_at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .name(Some(TTL_INDEX_NAME.to_string()))
+                            .expire_after(Some(max_age))
+                            .build(),
+                    )
+                    .build();
+                self.traffic_collection.create_index(index, None).await?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn insert_auth(&self, auth: &crate::AuthInfo) -> Result<(), mongodb::error::Error> {
         let filter = doc! {
             "issuer": &auth.issuer,
@@ -111,3 +457,97 @@ impl Mongo {
         Ok(())
     }
 }
+
+// Bodies stored once per SHA-256 in their own collection, keyed by the hash.
+pub struct MongoBodies {
+    body_collection: mongodb::Collection<Document>,
+}
+
+#[async_trait]
+impl BodyStore for MongoBodies {
+    async fn put_body(&self, sha256: &str, body: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let filter = doc! { "_id": sha256 };
+        let update = doc! {
+            "$setOnInsert": {
+                "size": body.len() as i64,
+                "body": Binary { subtype: BinarySubtype::Generic, bytes: body.to_vec() },
+            }
+        };
+        let options = mongodb::options::UpdateOptions::builder()
+            .upsert(Some(true))
+            .build();
+        self.body_collection
+            .update_one(filter, update, Some(options))
+            .await?;
+        Ok(())
+    }
+    async fn get_body(&self, sha256: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let document = self
+            .body_collection
+            .find_one(doc! { "_id": sha256 }, None)
+            .await?
+            .ok_or_else(|| format!("body {} not found", sha256))?;
+        Ok(document.get_binary_generic("body")?.clone())
+    }
+}
+
+impl MongoBodies {
+    pub async fn new(
+        db: &crate::service::config::Db,
+        collection_name: &str,
+    ) -> Result<Self, mongodb::error::Error> {
+        let con = Mongo::get_connection(&db.db_url).await?;
+        let database = Mongo::get_database(&con, &db.db_name).await?;
+        Ok(Self {
+            body_collection: database.collection::<Document>(collection_name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_filter() {
+        assert_eq!(Mongo::traffic_filter(&TrafficQuery::default()), doc! {});
+
+        let after = DateTime::from_millis(1_000);
+        let before = DateTime::from_millis(2_000);
+        let query = TrafficQuery {
+            host: Some("foo.com".to_string()),
+            path: Some("^/api/.*".to_string()),
+            captured_after: Some(after),
+            captured_before: Some(before),
+            body_text: Some("password".to_string()),
+            proxy_user: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Mongo::traffic_filter(&query),
+            doc! {
+                // Escaped, so the dot only matches a dot.
+                "host": { "$regex": "foo\\.com" },
+                "path": { "$regex": "^/api/.*" },
+                "captured_at": { "$gte": after, "$lt": before },
+                "$text": { "$search": "password" },
+                "proxy_user": "alice",
+            }
+        );
+
+        let query = TrafficQuery {
+            method: Some("get".to_string()),
+            status: Some(400..=499),
+            captured_before: Some(before),
+            ..Default::default()
+        };
+        assert_eq!(
+            Mongo::traffic_filter(&query),
+            doc! {
+                "method": { "$regex": "^get$", "$options": "i" },
+                "status": { "$gte": 400, "$lte": 499 },
+                "captured_at": { "$lt": before },
+            }
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use async_trait::async_trait;
+use openssl::ssl::{SslConnector, SslMethod};
+use postgres_openssl::MakeTlsConnector;
+use tokio_postgres::Client;
+
+use crate::data::retention::{cutoff, Policy, CREDENTIAL_HEADERS};
+use crate::data::Datastore;
+use crate::model::websocket::WebSocketSession;
+
+// Schema migrations are applied in order on startup and tracked in "{traffic}_migrations".
+// Append new migrations to the end - never edit one that has already shipped.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS {traffic} (
+        id BIGSERIAL PRIMARY KEY,
+        method TEXT NOT NULL,
+        scheme TEXT NOT NULL,
+        host TEXT NOT NULL,
+        path TEXT NOT NULL,
+        query TEXT NOT NULL,
+        request_headers JSONB NOT NULL,
+        request_body BYTEA NOT NULL,
+        request_body_string TEXT,
+        status INTEGER NOT NULL,
+        response_headers JSONB NOT NULL,
+        response_body BYTEA NOT NULL,
+        response_body_string TEXT,
+        version TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS {auth} (
+        id BIGSERIAL PRIMARY KEY,
+        issuer TEXT NOT NULL,
+        grant_type TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        redirect_url TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        UNIQUE (issuer, grant_type, client_id, redirect_url, scope)
+    );",
+    "ALTER TABLE {traffic}
+        ADD COLUMN IF NOT EXISTS request_body_ref JSONB,
+        ADD COLUMN IF NOT EXISTS response_body_ref JSONB;",
+    "ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS captured_at TIMESTAMPTZ NOT NULL DEFAULT now();
+    CREATE INDEX IF NOT EXISTS {traffic}_captured_at ON {traffic} (captured_at);",
+    "ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS encrypted_fields TEXT[] NOT NULL DEFAULT '{}';",
+    "ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS proxy_user TEXT;
+    CREATE INDEX IF NOT EXISTS {traffic}_proxy_user ON {traffic} (proxy_user);",
+    "ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS upstream_version TEXT;",
+    "ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS websocket_session TEXT;
+    CREATE TABLE IF NOT EXISTS {websocket} (
+        id TEXT PRIMARY KEY,
+        scheme TEXT NOT NULL,
+        host TEXT NOT NULL,
+        path TEXT NOT NULL,
+        proxy_user TEXT,
+        started_at TIMESTAMPTZ,
+        ended_at TIMESTAMPTZ,
+        encrypted_fields TEXT[] NOT NULL DEFAULT '{}'
+    );
+    CREATE TABLE IF NOT EXISTS {websocket}_messages (
+        session_id TEXT NOT NULL REFERENCES {websocket} (id) ON DELETE CASCADE,
+        sequence INTEGER NOT NULL,
+        direction TEXT NOT NULL,
+        opcode SMALLINT NOT NULL,
+        fin BOOLEAN NOT NULL,
+        payload BYTEA NOT NULL,
+        payload_string TEXT,
+        captured_at TIMESTAMPTZ,
+        PRIMARY KEY (session_id, sequence)
+    );",
+    "ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS request_truncated BOOLEAN NOT NULL DEFAULT FALSE;
+    ALTER TABLE {traffic} ADD COLUMN IF NOT EXISTS response_truncated BOOLEAN NOT NULL DEFAULT FALSE;",
+    "ALTER TABLE {websocket} ADD COLUMN IF NOT EXISTS truncated BOOLEAN NOT NULL DEFAULT FALSE;",
+];
+
+// Manage and store all datastore interactions.
+pub struct Postgres {
+    client: Client,
+    traffic_table: String,
+    insert_traffic_sql: String,
+    insert_auth_sql: String,
+    websocket_table: String,
+}
+
+#[async_trait]
+impl Datastore for Postgres {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_traffic(traffic).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_auth(auth).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_websocket_session(session).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn apply_retention(&self, policy: &Policy) -> Result<u64, Box<dyn std::error::Error>> {
+        let expired = self.expire_traffic(policy).await?;
+        Ok(expired + self.expire_websocket_sessions(policy).await?)
+    }
+}
+
+impl Postgres {
+    pub async fn new(
+        config: &crate::service::config::Config,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let db = &config.db;
+        let mut client = Self::get_connection(&db.db_url).await?;
+        let traffic_table = &db.traffic_collection_name;
+        let auth_table = &db.auth_collection_name;
+        let websocket_table = &db.websocket_collection_name;
+        Self::migrate(&mut client, traffic_table, auth_table, websocket_table).await?;
+        Ok(Self {
+            client,
+            traffic_table: traffic_table.to_string(),
+            insert_traffic_sql: format!(
+                "INSERT INTO {} (method, scheme, host, path, query, request_headers, request_body,
+                    request_body_string, status, response_headers, response_body,
+                    response_body_string, version, request_body_ref, response_body_ref, captured_at,
+                    encrypted_fields, proxy_user, upstream_version, websocket_session,
+                    request_truncated, response_truncated)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15,
+                    COALESCE($16, now()), $17, $18, $19, $20, $21, $22)",
+                traffic_table
+            ),
+            insert_auth_sql: format!(
+                "INSERT INTO {} (issuer, grant_type, client_id, redirect_url, scope)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (issuer, grant_type, client_id, redirect_url, scope)
+                DO UPDATE SET
+                    issuer = EXCLUDED.issuer,
+                    grant_type = EXCLUDED.grant_type,
+                    client_id = EXCLUDED.client_id,
+                    redirect_url = EXCLUDED.redirect_url,
+                    scope = EXCLUDED.scope",
+                auth_table
+            ),
+            websocket_table: websocket_table.to_string(),
+        })
+    }
+
+    async fn get_connection(
+        db_url: &str,
+    ) -> Result<Client, Box<dyn std::error::Error + Send + Sync>> {
+        // TLS is negotiated according to the sslmode in the connection string.
+        let connector = MakeTlsConnector::new(SslConnector::builder(SslMethod::tls())?.build());
+        let (client, connection) = tokio_postgres::connect(db_url, connector).await?;
+
+        // The connection object drives the socket and must be polled on its own task.
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!(
+                    "[ERROR] [src/data/postgres.rs] [get_connection]: (connection closed) {}",
+                    e
+                );
+            }
+        });
+
+        Ok(client)
+    }
+
+    async fn migrate(
+        client: &mut Client,
+        traffic_table: &str,
+        auth_table: &str,
+        websocket_table: &str,
+    ) -> Result<(), tokio_postgres::Error> {
+        let migrations_table = format!("{}_migrations", traffic_table);
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    version INTEGER PRIMARY KEY,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+                migrations_table
+            ))
+            .await?;
+        let row = client
+            .query_one(
+                &format!("SELECT COALESCE(MAX(version), 0) FROM {}", migrations_table),
+                &[],
+            )
+            .await?;
+        let applied: i32 = row.get(0);
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            let version = (index + 1) as i32;
+            let sql = migration
+                .replace("{traffic}", traffic_table)
+                .replace("{auth}", auth_table)
+                .replace("{websocket}", websocket_table);
+            let transaction = client.transaction().await?;
+            transaction.batch_execute(&sql).await?;
+            transaction
+                .execute(
+                    &format!("INSERT INTO {} (version) VALUES ($1)", migrations_table),
+                    &[&version],
+                )
+                .await?;
+            transaction.commit().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn insert_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), tokio_postgres::Error> {
+        let request_headers = serde_json::to_value(&traffic.request_headers).unwrap();
+        let response_headers = serde_json::to_value(&traffic.response_headers).unwrap();
+        let status = traffic.status as i32;
+        let request_body_ref = traffic
+            .request_body_ref
+            .as_ref()
+            .map(|body_ref| serde_json::to_value(body_ref).unwrap());
+        let response_body_ref = traffic
+            .response_body_ref
+            .as_ref()
+            .map(|body_ref| serde_json::to_value(body_ref).unwrap());
+        let captured_at = traffic
+            .captured_at
+            .map(|captured_at| captured_at.to_system_time());
+        self.client
+            .execute(
+                &self.insert_traffic_sql,
+                &[
+                    &traffic.method,
+                    &traffic.scheme,
+                    &traffic.host,
+                    &traffic.path,
+                    &traffic.query,
+                    &request_headers,
+                    &traffic.request_body,
+                    &traffic.request_body_string,
+                    &status,
+                    &response_headers,
+                    &traffic.response_body,
+                    &traffic.response_body_string,
+                    &traffic.version,
+                    &request_body_ref,
+                    &response_body_ref,
+                    &captured_at,
+                    &traffic.encrypted_fields,
+                    &traffic.proxy_user,
+                    &traffic.upstream_version,
+                    &traffic.websocket_session,
+                    &traffic.request_truncated,
+                    &traffic.response_truncated,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn expire_traffic(&self, policy: &Policy) -> Result<u64, tokio_postgres::Error> {
+        let table = &self.traffic_table;
+        let mut expired = 0;
+        if let Some(max_age) = policy.max_age {
+            expired += self
+                .client
+                .execute(
+                    &format!("DELETE FROM {} WHERE captured_at < $1", table),
+                    &[&cutoff(max_age)],
+                )
+                .await?;
+        }
+        for host_policy in &policy.hosts {
+            expired += self
+                .client
+                .execute(
+                    &format!(
+                        "DELETE FROM {} WHERE strpos(host, $1) > 0 AND captured_at < $2",
+                        table
+                    ),
+                    &[&host_policy.host, &cutoff(host_policy.max_age)],
+                )
+                .await?;
+        }
+        if let Some(credentialed_max_age) = policy.credentialed_max_age {
+            let headers: Vec<String> = CREDENTIAL_HEADERS.iter().map(|h| h.to_string()).collect();
+            expired += self
+                .client
+                .execute(
+                    &format!(
+                        "DELETE FROM {} WHERE request_headers ?| $1 AND captured_at < $2",
+                        table
+                    ),
+                    &[&headers, &cutoff(credentialed_max_age)],
+                )
+                .await?;
+        }
+        if let Some(max_documents) = policy.max_documents {
+            expired += self
+                .client
+                .execute(
+                    &format!(
+                        "DELETE FROM {0} WHERE id <= (SELECT id FROM {0} ORDER BY id DESC OFFSET $1 LIMIT 1)",
+                        table
+                    ),
+                    &[&(max_documents as i64)],
+                )
+                .await?;
+        }
+        Ok(expired)
+    }
+
+    // Sessions go by the age limits, from when they started. Their frames go with them.
+    pub async fn expire_websocket_sessions(
+        &self,
+        policy: &Policy,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let table = &self.websocket_table;
+        let mut expired = 0;
+        if let Some(max_age) = policy.max_age {
+            expired += self
+                .client
+                .execute(
+                    &format!("DELETE FROM {} WHERE started_at < $1", table),
+                    &[&cutoff(max_age)],
+                )
+                .await?;
+        }
+        for host_policy in &policy.hosts {
+            expired += self
+                .client
+                .execute(
+                    &format!(
+                        "DELETE FROM {} WHERE strpos(host, $1) > 0 AND started_at < $2",
+                        table
+                    ),
+                    &[&host_policy.host, &cutoff(host_policy.max_age)],
+                )
+                .await?;
+        }
+        Ok(expired)
+    }
+
+    // The session and its frames in one statement, so a session is never stored half written.
+    pub async fn insert_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), tokio_postgres::Error> {
+        let table = &self.websocket_table;
+        let messages = &session.messages;
+        let sequences: Vec<i32> = (0..messages.len() as i32).collect();
+        let directions: Vec<&str> = messages.iter().map(|m| m.direction.as_str()).collect();
+        let opcodes: Vec<i16> = messages.iter().map(|m| m.opcode as i16).collect();
+        let fins: Vec<bool> = messages.iter().map(|m| m.fin).collect();
+        let payloads: Vec<&[u8]> = messages.iter().map(|m| m.payload.as_slice()).collect();
+        let payload_strings: Vec<Option<&str>> = messages
+            .iter()
+            .map(|m| m.payload_string.as_deref())
+            .collect();
+        let captured_at: Vec<Option<std::time::SystemTime>> = messages
+            .iter()
+            .map(|m| m.captured_at.map(|at| at.to_system_time()))
+            .collect();
+        self.client
+            .execute(
+                &format!(
+                    "WITH session AS (
+                        INSERT INTO {0} (id, scheme, host, path, proxy_user, started_at, ended_at,
+                            encrypted_fields, truncated)
+                        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $16)
+                    )
+                    INSERT INTO {0}_messages (session_id, sequence, direction, opcode, fin, payload,
+                        payload_string, captured_at)
+                    SELECT $1, * FROM UNNEST($9::INTEGER[], $10::TEXT[], $11::SMALLINT[],
+                        $12::BOOLEAN[], $13::BYTEA[], $14::TEXT[], $15::TIMESTAMPTZ[])",
+                    table
+                ),
+                &[
+                    &session.id,
+                    &session.scheme,
+                    &session.host,
+                    &session.path,
+                    &session.proxy_user,
+                    &session.started_at.map(|at| at.to_system_time()),
+                    &session.ended_at.map(|at| at.to_system_time()),
+                    &session.encrypted_fields,
+                    &sequences,
+                    &directions,
+                    &opcodes,
+                    &fins,
+                    &payloads,
+                    &payload_strings,
+                    &captured_at,
+                    &session.truncated,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn insert_auth(&self, auth: &crate::AuthInfo) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .execute(
+                &self.insert_auth_sql,
+                &[
+                    &auth.issuer,
+                    &auth.grant_type,
+                    &auth.client_id,
+                    &auth.redirect_url,
+                    &auth.scope,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use mongodb::bson::DateTime;
+use regex::Regex;
+use std::cmp::Ordering;
+use std::ops::RangeInclusive;
+
+use crate::model::traffic::Traffic;
+
+// A traffic field that can be sorted on or listed with Datastore::distinct_traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Method,
+    Scheme,
+    Host,
+    Path,
+    Status,
+    CapturedAt,
+    ProxyUser,
+}
+
+impl Field {
+    // The field's name in stored traffic.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Method => "method",
+            Self::Scheme => "scheme",
+            Self::Host => "host",
+            Self::Path => "path",
+            Self::Status => "status",
+            Self::CapturedAt => "captured_at",
+            Self::ProxyUser => "proxy_user",
+        }
+    }
+
+    pub fn value(&self, traffic: &Traffic) -> String {
+        match self {
+            Self::Method => traffic.method.clone(),
+            Self::Scheme => traffic.scheme.clone(),
+            Self::Host => traffic.host.clone(),
+            Self::Path => traffic.path.clone(),
+            Self::Status => traffic.status.to_string(),
+            Self::CapturedAt => traffic
+                .captured_at
+                .map(|captured_at| captured_at.to_string())
+                .unwrap_or_default(),
+            Self::ProxyUser => traffic.proxy_user.clone().unwrap_or_default(),
+        }
+    }
+
+    fn compare(&self, a: &Traffic, b: &Traffic) -> Ordering {
+        match self {
+            Self::Status => a.status.cmp(&b.status),
+            Self::CapturedAt => a.captured_at.cmp(&b.captured_at),
+            _ => self.value(a).cmp(&self.value(b)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sort {
+    pub field: Field,
+    pub descending: bool,
+}
+
+// Which traffic to read back, independent of the datastore. Unset fields match everything.
+// host matches like the [filter] host lists, proxy_user exactly, path is a regex, and body_text searches the
+// decoded body strings - Mongo uses its text index, so it matches whole words there.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficQuery {
+    pub host: Option<String>,
+    pub method: Option<String>,
+    pub status: Option<RangeInclusive<u16>>,
+    pub path: Option<String>,
+    pub captured_after: Option<DateTime>,
+    pub captured_before: Option<DateTime>,
+    pub body_text: Option<String>,
+    pub proxy_user: Option<String>,
+    pub sort: Option<Sort>,
+    pub skip: u64,
+    pub limit: Option<u64>,
+}
+
+impl TrafficQuery {
+    // For datastores that filter in process. Fails only on an invalid path regex.
+    pub fn matcher(&self) -> Result<impl Fn(&Traffic) -> bool + '_, regex::Error> {
+        let path = self.path.as_deref().map(Regex::new).transpose()?;
+        let body_text = self.body_text.as_ref().map(|text| text.to_lowercase());
+        Ok(move |traffic: &Traffic| {
+            self.host
+                .as_ref()
+                .is_none_or(|host| traffic.host.contains(host.as_str()))
+                && self
+                    .method
+                    .as_ref()
+                    .is_none_or(|method| traffic.method.eq_ignore_ascii_case(method))
+                && self
+                    .status
+                    .as_ref()
+                    .is_none_or(|status| status.contains(&traffic.status))
+                && path
+                    .as_ref()
+                    .is_none_or(|path| path.is_match(&traffic.path))
+                && self
+                    .captured_after
+                    .is_none_or(|after| traffic.captured_at.is_some_and(|at| at >= after))
+                && self
+                    .captured_before
+                    .is_none_or(|before| traffic.captured_at.is_some_and(|at| at < before))
+                && self
+                    .proxy_user
+                    .as_ref()
+                    .is_none_or(|user| traffic.proxy_user.as_ref() == Some(user))
+                && body_text.as_ref().is_none_or(|text| {
+                    [&traffic.request_body_string, &traffic.response_body_string]
+                        .iter()
+                        .any(|body| {
+                            body.as_ref()
+                                .is_some_and(|body| body.to_lowercase().contains(text.as_str()))
+                        })
+                })
+        })
+    }
+
+    // Sort and page traffic that has already been matched.
+    pub fn page(&self, mut traffic: Vec<Traffic>) -> Vec<Traffic> {
+        if let Some(sort) = self.sort {
+            traffic.sort_by(|a, b| match sort.descending {
+                true => sort.field.compare(b, a),
+                false => sort.field.compare(a, b),
+            });
+        }
+        traffic
+            .into_iter()
+            .skip(self.skip as usize)
+            .take(self.limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect()
+    }
+}
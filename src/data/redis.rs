@@ -0,0 +1,161 @@
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+
+use crate::data::retention::{cutoff, Policy};
+use crate::data::Datastore;
+use crate::model::auth::AuthInfo;
+use crate::model::traffic::Traffic;
+use crate::model::websocket::WebSocketSession;
+
+// Publish traffic to Redis streams so downstream consumers can tail it in real time.
+pub struct RedisStreams {
+    connection: ConnectionManager,
+    traffic_stream: String,
+    auth_stream: String,
+    websocket_stream: String,
+    max_len: usize,
+}
+
+#[async_trait]
+impl Datastore for RedisStreams {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.xadd_traffic(traffic).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        match self.xadd_auth(auth).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.xadd_websocket_session(session).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn apply_retention(&self, policy: &Policy) -> Result<u64, Box<dyn std::error::Error>> {
+        match self.trim_traffic(policy).await {
+            Ok(expired) => Ok(expired),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+impl RedisStreams {
+    pub async fn new(
+        config: &crate::service::config::Config,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let config = config
+            .redis
+            .as_ref()
+            .ok_or("[redis] must be configured to use the Redis datastore.")?;
+        let client = redis::Client::open(config.url.as_str())?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(Self {
+            connection,
+            traffic_stream: config.traffic_stream.clone(),
+            auth_stream: config.auth_stream.clone(),
+            websocket_stream: config.websocket_stream.clone(),
+            max_len: config.max_len,
+        })
+    }
+
+    // XADD {stream} MAXLEN ~ {max_len} * host {host} traffic {json}
+    pub async fn xadd_traffic(&self, traffic: &Traffic) -> Result<(), redis::RedisError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("XADD")
+            .arg(&self.traffic_stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg("host")
+            .arg(&traffic.host)
+            .arg("traffic")
+            .arg(traffic.get_json())
+            .query_async::<_, String>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    // Stream IDs start with the capture time in millis, so XTRIM MINID expires by age.
+    // Per-host and credentialed limits would need entries removed from the middle of a stream.
+    pub async fn trim_traffic(&self, policy: &Policy) -> Result<u64, redis::RedisError> {
+        let mut connection = self.connection.clone();
+        let mut expired = 0;
+        if let Some(max_age) = policy.max_age {
+            let min_id = cutoff(max_age)
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            // Sessions too, which are added when they close.
+            for stream in [&self.traffic_stream, &self.websocket_stream] {
+                expired += redis::cmd("XTRIM")
+                    .arg(stream)
+                    .arg("MINID")
+                    .arg("~")
+                    .arg(min_id as u64)
+                    .query_async::<_, u64>(&mut connection)
+                    .await?;
+            }
+        }
+        if let Some(max_documents) = policy.max_documents {
+            expired += redis::cmd("XTRIM")
+                .arg(&self.traffic_stream)
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(max_documents)
+                .query_async::<_, u64>(&mut connection)
+                .await?;
+        }
+        Ok(expired)
+    }
+
+    // XADD {stream} MAXLEN ~ {max_len} * issuer {issuer} authinfo {json}
+    pub async fn xadd_auth(&self, auth: &AuthInfo) -> Result<(), redis::RedisError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("XADD")
+            .arg(&self.auth_stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg("issuer")
+            .arg(&auth.issuer)
+            .arg("authinfo")
+            .arg(auth.get_json())
+            .query_async::<_, String>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    // XADD {stream} MAXLEN ~ {max_len} * host {host} session {json}
+    pub async fn xadd_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), redis::RedisError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("XADD")
+            .arg(&self.websocket_stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg("host")
+            .arg(&session.host)
+            .arg("session")
+            .arg(session.get_json())
+            .query_async::<_, String>(&mut connection)
+            .await?;
+        Ok(())
+    }
+}
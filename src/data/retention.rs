@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::data::Datastore;
+
+// How long recorded traffic is kept. Every limit is optional.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub max_age: Option<Duration>,
+    pub max_documents: Option<u64>,
+    // Traffic carrying a cookie or authorization header holds session tokens.
+    pub credentialed_max_age: Option<Duration>,
+    pub hosts: Vec<HostPolicy>,
+}
+
+// A max age for traffic whose host contains `host`, like the [filter] host lists.
+// The global max_age still applies, so an override can only shorten how long a host is kept.
+#[derive(Clone, Debug)]
+pub struct HostPolicy {
+    pub host: String,
+    pub max_age: Duration,
+}
+
+// Request headers whose presence marks traffic as credentialed.
+pub const CREDENTIAL_HEADERS: &[&str] = &["cookie", "authorization"];
+
+impl Policy {
+    pub fn from_config(retention: &crate::service::config::Retention) -> Self {
+        Self {
+            max_age: retention.max_age_secs.map(Duration::from_secs),
+            max_documents: retention.max_documents,
+            credentialed_max_age: retention.credentialed_max_age_secs.map(Duration::from_secs),
+            hosts: retention
+                .hosts
+                .iter()
+                .map(|host| HostPolicy {
+                    host: host.host.clone(),
+                    max_age: Duration::from_secs(host.max_age_secs),
+                })
+                .collect(),
+        }
+    }
+}
+
+// The capture time before which traffic of this age has expired.
+pub fn cutoff(max_age: Duration) -> SystemTime {
+    SystemTime::now()
+        .checked_sub(max_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+// Enforce the policy against the datastore on an interval, until `stop` is cancelled.
+pub fn spawn(
+    datastore: Arc<dyn Datastore>,
+    policy: Policy,
+    interval: Duration,
+    stop: CancellationToken,
+) -> Result<(), String> {
+    // tokio::time::interval panics on a zero period.
+    if interval.is_zero() {
+        return Err("[retention] interval_secs must be at least 1.".to_string());
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            let result = tokio::select! {
+                _ = stop.cancelled() => return,
+                result = async {
+                    ticker.tick().await;
+                    datastore.apply_retention(&policy).await.map_err(|e| e.to_string())
+                } => result,
+            };
+            match result {
+                Ok(0) => {}
+                Ok(expired) => println!("[ohm] Retention expired {} records.", expired),
+                Err(e) => eprintln!("[ERROR] [src/data/retention.rs] [spawn]: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
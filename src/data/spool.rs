@@ -0,0 +1,551 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::task::AbortOnDropHandle;
+
+use crate::data::Datastore;
+use crate::model::auth::AuthInfo;
+use crate::model::traffic::Traffic;
+use crate::model::websocket::WebSocketSession;
+
+#[derive(Serialize, Deserialize)]
+enum SpoolEntry {
+    Traffic(Box<Traffic>),
+    AuthInfo(AuthInfo),
+    WebSocketSession(Box<WebSocketSession>),
+}
+
+// Spooled entries by sequence number, with their size on disk.
+// An entry is pending from the moment its sequence is taken, while it is still being written.
+struct SpoolIndex {
+    next_sequence: u64,
+    entries: BTreeMap<u64, u64>,
+    writing: BTreeSet<u64>,
+    bytes: u64,
+}
+
+struct SpoolState {
+    datastore: Box<dyn Datastore>,
+    directory: PathBuf,
+    max_bytes: u64,
+    retry_initial: Duration,
+    retry_max: Duration,
+    max_attempts: u32,
+    index: Mutex<SpoolIndex>,
+    notify: Notify,
+}
+
+// Write-ahead spool in front of a datastore.
+// Writes that fail are persisted to a local directory and replayed in order by a background task,
+// retrying with exponential backoff until the datastore recovers. While anything is pending, new
+// writes queue up behind it so the datastore still receives them in order.
+// An entry that still fails after max_attempts is set aside as "{sequence}.failed" so it can't
+// hold up the rest - renaming it back to ".json" queues it again on the next run.
+pub struct Spool {
+    state: Arc<SpoolState>,
+    // Replay holds the state too, so it has to be stopped when the Spool is dropped.
+    _replay: AbortOnDropHandle<()>,
+}
+
+#[async_trait]
+impl Datastore for Spool {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.pending().0 == 0 {
+            let error = match self.state.datastore.add_traffic(traffic).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e.to_string(),
+            };
+            println!(
+                "[WARN] [src/data/spool.rs] [add_traffic]: (spooling failed write) {}",
+                error
+            );
+        }
+        self.spill_traffic(traffic).await
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        if self.pending().0 == 0 {
+            let error = match self.state.datastore.add_authinfo(auth).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e.to_string(),
+            };
+            println!(
+                "[WARN] [src/data/spool.rs] [add_authinfo]: (spooling failed write) {}",
+                error
+            );
+        }
+        match self.persist(&SpoolEntry::AuthInfo(auth.clone())).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.pending().0 == 0 {
+            let error = match self.state.datastore.add_websocket_session(session).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e.to_string(),
+            };
+            println!(
+                "[WARN] [src/data/spool.rs] [add_websocket_session]: (spooling failed write) {}",
+                error
+            );
+        }
+        match self
+            .persist(&SpoolEntry::WebSocketSession(Box::new(session.clone())))
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.pending().0 == 0 {
+            let error = match self.state.datastore.add_traffic_batch(traffic).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e.to_string(),
+            };
+            // A partially written batch is spooled whole, so replay may repeat a few entries.
+            println!(
+                "[WARN] [src/data/spool.rs] [add_traffic_batch]: (spooling failed batch) {}",
+                error
+            );
+        }
+        for t in traffic {
+            self.spill_traffic(t).await?;
+        }
+        Ok(())
+    }
+    async fn apply_retention(
+        &self,
+        policy: &crate::data::retention::Policy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        self.state.datastore.apply_retention(policy).await
+    }
+    async fn find_traffic(
+        &self,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<crate::Traffic>, Box<dyn std::error::Error>> {
+        self.state.datastore.find_traffic(query).await
+    }
+    async fn distinct_traffic(
+        &self,
+        field: crate::data::query::Field,
+        query: &crate::data::query::TrafficQuery,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.state.datastore.distinct_traffic(field, query).await
+    }
+    // Spooled entries are already on disk and get replayed by the next run.
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (entries, bytes) = self.pending();
+        if entries > 0 {
+            println!(
+                "[ohm] Spool {:?} keeps {} pending entries ({} bytes) for the next run.",
+                self.state.directory, entries, bytes
+            );
+        }
+        self.state.datastore.flush().await
+    }
+}
+
+impl Spool {
+    pub fn new(
+        datastore: Box<dyn Datastore>,
+        directory: &str,
+        max_bytes: u64,
+        retry_initial: Duration,
+        retry_max: Duration,
+        max_attempts: u32,
+    ) -> Self {
+        let directory = PathBuf::from(directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let index = Self::load_index(&directory).unwrap();
+        if !index.entries.is_empty() {
+            println!(
+                "[ohm] Spool {:?} has {} pending entries ({} bytes) to replay.",
+                directory,
+                index.entries.len(),
+                index.bytes
+            );
+        }
+
+        let state = Arc::new(SpoolState {
+            datastore,
+            directory,
+            max_bytes,
+            retry_initial,
+            retry_max,
+            max_attempts,
+            index: Mutex::new(index),
+            notify: Notify::new(),
+        });
+        let replay = AbortOnDropHandle::new(tokio::spawn(Self::replay(state.clone())));
+        Self {
+            state,
+            _replay: replay,
+        }
+    }
+
+    // Spool traffic without trying the datastore first, e.g. when a write queue overflows.
+    pub async fn spill_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self
+            .persist(&SpoolEntry::Traffic(Box::new(traffic.clone())))
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // The number of entries and bytes waiting to be replayed.
+    pub fn pending(&self) -> (usize, u64) {
+        let index = self.state.index.lock().unwrap();
+        (index.entries.len() + index.writing.len(), index.bytes)
+    }
+
+    // Pick up entries left behind by a previous run - "{sequence}.json", zero padded so they sort.
+    fn load_index(directory: &PathBuf) -> Result<SpoolIndex, std::io::Error> {
+        let mut index = SpoolIndex {
+            next_sequence: 0,
+            entries: BTreeMap::new(),
+            writing: BTreeSet::new(),
+            bytes: 0,
+        };
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().unwrap_or_default();
+            if let Some(sequence) = name
+                .strip_suffix(".json")
+                .and_then(|sequence| sequence.parse::<u64>().ok())
+            {
+                let size = entry.metadata()?.len();
+                index.entries.insert(sequence, size);
+                index.bytes += size;
+                index.next_sequence = index.next_sequence.max(sequence + 1);
+            }
+        }
+        Ok(index)
+    }
+
+    fn entry_path(directory: &std::path::Path, sequence: u64) -> PathBuf {
+        directory.join(format!("{:020}.json", sequence))
+    }
+
+    async fn persist(&self, entry: &SpoolEntry) -> Result<(), std::io::Error> {
+        let bytes = serde_json::to_vec(entry)?;
+        let size = bytes.len() as u64;
+        let sequence = {
+            let mut index = self.state.index.lock().unwrap();
+            if index.bytes + size > self.state.max_bytes {
+                return Err(std::io::Error::other(format!(
+                    "spool is full ({} bytes pending), dropping entry",
+                    index.bytes
+                )));
+            }
+            // Reserve the space now so concurrent writers can't overshoot the cap, and count the
+            // entry as pending so new writes queue behind it instead of overtaking it.
+            index.bytes += size;
+            index.next_sequence += 1;
+            let sequence = index.next_sequence - 1;
+            index.writing.insert(sequence);
+            sequence
+        };
+
+        // Write then rename, so the replay task never reads a partial entry.
+        let path = Self::entry_path(&self.state.directory, sequence);
+        let temporary_path = path.with_extension("tmp");
+        let written = async {
+            tokio::fs::write(&temporary_path, &bytes).await?;
+            tokio::fs::rename(&temporary_path, &path).await
+        }
+        .await;
+
+        let mut index = self.state.index.lock().unwrap();
+        index.writing.remove(&sequence);
+        // Either way the replay task may be waiting on this sequence.
+        self.state.notify.notify_one();
+        match written {
+            Ok(()) => {
+                index.entries.insert(sequence, size);
+                Ok(())
+            }
+            Err(e) => {
+                index.bytes -= size;
+                Err(e)
+            }
+        }
+    }
+
+    async fn replay(state: Arc<SpoolState>) {
+        let mut backoff = state.retry_initial;
+        let mut attempts = 0;
+        loop {
+            let next = {
+                let index = state.index.lock().unwrap();
+                let next = index
+                    .entries
+                    .iter()
+                    .next()
+                    .map(|(sequence, size)| (*sequence, *size));
+                // An earlier entry that is still being written goes first.
+                match (next, index.writing.iter().next()) {
+                    (Some((sequence, _)), Some(writing)) if *writing < sequence => None,
+                    _ => next,
+                }
+            };
+            let (sequence, size) = match next {
+                Some(next) => next,
+                None => {
+                    state.notify.notified().await;
+                    continue;
+                }
+            };
+
+            let path = Self::entry_path(&state.directory, sequence);
+            let result = match tokio::fs::read(&path).await {
+                Ok(bytes) => match serde_json::from_slice::<SpoolEntry>(&bytes) {
+                    Ok(SpoolEntry::Traffic(traffic)) => state
+                        .datastore
+                        .add_traffic(&traffic)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Ok(SpoolEntry::AuthInfo(auth)) => state
+                        .datastore
+                        .add_authinfo(&auth)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Ok(SpoolEntry::WebSocketSession(session)) => state
+                        .datastore
+                        .add_websocket_session(&session)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => {
+                        // Set unreadable entries aside rather than blocking the spool on them.
+                        eprintln!(
+                            "[ERROR] [src/data/spool.rs] [replay]: (unreadable entry {:?}) {}",
+                            path, e
+                        );
+                        let _ = tokio::fs::rename(&path, path.with_extension("corrupt")).await;
+                        Ok(())
+                    }
+                },
+                Err(e) => Err(e.to_string()),
+            };
+
+            match result {
+                Ok(()) => {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    let mut index = state.index.lock().unwrap();
+                    index.entries.remove(&sequence);
+                    index.bytes -= size;
+                    if index.entries.is_empty() {
+                        println!(
+                            "[ohm] Spool {:?} replayed, datastore caught up.",
+                            state.directory
+                        );
+                    }
+                    attempts = 0;
+                    backoff = state.retry_initial;
+                }
+                Err(e) if attempts + 1 >= state.max_attempts => {
+                    eprintln!(
+                        "[ERROR] [src/data/spool.rs] [replay]: (setting {:?} aside after {} attempts) {}",
+                        path, state.max_attempts, e
+                    );
+                    let _ = tokio::fs::rename(&path, path.with_extension("failed")).await;
+                    let mut index = state.index.lock().unwrap();
+                    index.entries.remove(&sequence);
+                    index.bytes -= size;
+                    attempts = 0;
+                    backoff = state.retry_initial;
+                }
+                Err(e) => {
+                    attempts += 1;
+                    let (count, bytes) = {
+                        let index = state.index.lock().unwrap();
+                        (index.entries.len() + index.writing.len(), index.bytes)
+                    };
+                    eprintln!(
+                        "[ERROR] [src/data/spool.rs] [replay]: (retrying in {:?}, {} entries / {} bytes pending) {}",
+                        backoff, count, bytes, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(state.retry_max);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // A datastore that records what it was given and can be switched off.
+    struct Flaky {
+        available: Arc<AtomicBool>,
+        stored: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Datastore for Flaky {
+        async fn add_traffic(&self, traffic: &Traffic) -> Result<(), Box<dyn std::error::Error>> {
+            match self.available.load(Ordering::SeqCst) {
+                true => {
+                    self.stored.lock().unwrap().push(traffic.path.clone());
+                    Ok(())
+                }
+                false => Err("datastore unavailable".into()),
+            }
+        }
+        async fn add_authinfo(&self, _auth: &AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spool_replays_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = std::env::temp_dir().join(format!("ohm-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let available = Arc::new(AtomicBool::new(false));
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let flaky = Flaky {
+            available: available.clone(),
+            stored: stored.clone(),
+        };
+        let spool = Spool::new(
+            Box::new(flaky),
+            directory.to_str().unwrap(),
+            1024 * 1024,
+            Duration::from_millis(10),
+            Duration::from_millis(20),
+            u32::MAX,
+        );
+
+        spool
+            .add_traffic(&Traffic::fixture("foobar.com", "/one"))
+            .await?;
+        spool
+            .add_traffic(&Traffic::fixture("foobar.com", "/two"))
+            .await?;
+        assert_eq!(spool.pending().0, 2);
+        assert!(stored.lock().unwrap().is_empty());
+
+        available.store(true, Ordering::SeqCst);
+        for _ in 0..100 {
+            if spool.pending().0 == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(spool.pending(), (0, 0));
+        assert_eq!(*stored.lock().unwrap(), vec!["/one", "/two"]);
+
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_disk_cap() {
+        let directory = std::env::temp_dir().join(format!("ohm-spool-cap-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let flaky = Flaky {
+            available: Arc::new(AtomicBool::new(false)),
+            stored: Arc::new(Mutex::new(Vec::new())),
+        };
+        let spool = Spool::new(
+            Box::new(flaky),
+            directory.to_str().unwrap(),
+            16,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            u32::MAX,
+        );
+
+        assert!(spool
+            .add_traffic(&Traffic::fixture("foobar.com", "/one"))
+            .await
+            .is_err());
+        assert_eq!(spool.pending(), (0, 0));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool_sets_failing_entry_aside() -> Result<(), Box<dyn std::error::Error>> {
+        // Rejects one path for good, as a datastore would a document it can never accept.
+        struct Picky {
+            stored: Arc<Mutex<Vec<String>>>,
+        }
+        #[async_trait]
+        impl Datastore for Picky {
+            async fn add_traffic(
+                &self,
+                traffic: &Traffic,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                if traffic.path == "/poison" {
+                    return Err("document too large".into());
+                }
+                self.stored.lock().unwrap().push(traffic.path.clone());
+                Ok(())
+            }
+            async fn add_authinfo(
+                &self,
+                _auth: &AuthInfo,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+        }
+
+        let directory =
+            std::env::temp_dir().join(format!("ohm-spool-aside-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let spool = Spool::new(
+            Box::new(Picky {
+                stored: stored.clone(),
+            }),
+            directory.to_str().unwrap(),
+            1024 * 1024,
+            Duration::from_millis(1),
+            Duration::from_millis(1),
+            3,
+        );
+
+        spool
+            .add_traffic(&Traffic::fixture("foobar.com", "/poison"))
+            .await?;
+        spool
+            .add_traffic(&Traffic::fixture("foobar.com", "/after"))
+            .await?;
+        for _ in 0..100 {
+            if spool.pending().0 == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(spool.pending(), (0, 0));
+        assert_eq!(*stored.lock().unwrap(), vec!["/after"]);
+        assert!(Spool::entry_path(&directory, 0)
+            .with_extension("failed")
+            .exists());
+
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
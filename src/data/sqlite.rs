@@ -0,0 +1,576 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::data::retention::{cutoff, Policy, CREDENTIAL_HEADERS};
+use crate::data::Datastore;
+use crate::model::auth::AuthInfo;
+use crate::model::traffic::Traffic;
+use crate::model::websocket::WebSocketSession;
+
+// Schema migrations are applied in order on startup and tracked in "{traffic}_migrations".
+// Append new migrations to the end - never edit one that has already shipped.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS {traffic} (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        method TEXT NOT NULL,
+        scheme TEXT NOT NULL,
+        host TEXT NOT NULL,
+        path TEXT NOT NULL,
+        query TEXT NOT NULL,
+        request_headers TEXT NOT NULL,
+        request_body BLOB NOT NULL,
+        request_body_string TEXT,
+        status INTEGER NOT NULL,
+        response_headers TEXT NOT NULL,
+        response_body BLOB NOT NULL,
+        response_body_string TEXT,
+        version TEXT NOT NULL,
+        request_body_ref TEXT,
+        response_body_ref TEXT,
+        captured_at INTEGER NOT NULL,
+        encrypted_fields TEXT NOT NULL DEFAULT '[]'
+    );
+    CREATE INDEX IF NOT EXISTS {traffic}_host ON {traffic} (host);
+    CREATE INDEX IF NOT EXISTS {traffic}_captured_at ON {traffic} (captured_at);
+    CREATE TABLE IF NOT EXISTS {auth} (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        issuer TEXT NOT NULL,
+        grant_type TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        redirect_url TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        UNIQUE (issuer, grant_type, client_id, redirect_url, scope)
+    );",
+    // Full text search over the decoded bodies, kept in step with the traffic table by triggers.
+    "CREATE VIRTUAL TABLE IF NOT EXISTS {traffic}_fts USING fts5(
+        request_body_string, response_body_string, content='{traffic}', content_rowid='id'
+    );
+    CREATE TRIGGER IF NOT EXISTS {traffic}_fts_insert AFTER INSERT ON {traffic} BEGIN
+        INSERT INTO {traffic}_fts (rowid, request_body_string, response_body_string)
+        VALUES (new.id, new.request_body_string, new.response_body_string);
+    END;
+    CREATE TRIGGER IF NOT EXISTS {traffic}_fts_delete AFTER DELETE ON {traffic} BEGIN
+        INSERT INTO {traffic}_fts ({traffic}_fts, rowid, request_body_string, response_body_string)
+        VALUES ('delete', old.id, old.request_body_string, old.response_body_string);
+    END;",
+    "ALTER TABLE {traffic} ADD COLUMN proxy_user TEXT;
+    CREATE INDEX IF NOT EXISTS {traffic}_proxy_user ON {traffic} (proxy_user);",
+    "ALTER TABLE {traffic} ADD COLUMN upstream_version TEXT;",
+    "ALTER TABLE {traffic} ADD COLUMN websocket_session TEXT;
+    CREATE TABLE IF NOT EXISTS {websocket} (
+        id TEXT PRIMARY KEY,
+        scheme TEXT NOT NULL,
+        host TEXT NOT NULL,
+        path TEXT NOT NULL,
+        proxy_user TEXT,
+        started_at INTEGER,
+        ended_at INTEGER,
+        encrypted_fields TEXT NOT NULL DEFAULT '[]'
+    );
+    CREATE TABLE IF NOT EXISTS {websocket}_messages (
+        session_id TEXT NOT NULL REFERENCES {websocket} (id) ON DELETE CASCADE,
+        sequence INTEGER NOT NULL,
+        direction TEXT NOT NULL,
+        opcode INTEGER NOT NULL,
+        fin INTEGER NOT NULL,
+        payload BLOB NOT NULL,
+        payload_string TEXT,
+        captured_at INTEGER,
+        PRIMARY KEY (session_id, sequence)
+    );",
+    "ALTER TABLE {traffic} ADD COLUMN request_truncated INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE {traffic} ADD COLUMN response_truncated INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE {websocket} ADD COLUMN truncated INTEGER NOT NULL DEFAULT 0;",
+];
+
+// A whole capture in one SQLite file that can be handed around and opened with standard tools.
+// rusqlite is blocking, so every statement runs on the blocking thread pool.
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+    traffic_table: String,
+    insert_traffic_sql: String,
+    insert_auth_sql: String,
+    websocket_table: String,
+}
+
+#[async_trait]
+impl Datastore for Sqlite {
+    async fn add_traffic(
+        &self,
+        traffic: &crate::Traffic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_traffic(vec![traffic.clone()]).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_authinfo(&self, auth: &crate::AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_auth(auth.clone()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_traffic_batch(
+        &self,
+        traffic: &[crate::Traffic],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_traffic(traffic.to_vec()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn add_websocket_session(
+        &self,
+        session: &WebSocketSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.insert_websocket_session(session.clone()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    async fn apply_retention(&self, policy: &Policy) -> Result<u64, Box<dyn std::error::Error>> {
+        let expired = self.expire_traffic(policy.clone()).await?;
+        Ok(expired + self.expire_websocket_sessions(policy.clone()).await?)
+    }
+}
+
+impl Sqlite {
+    pub async fn new(
+        config: &crate::service::config::Config,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let sqlite = config
+            .sqlite
+            .as_ref()
+            .ok_or("[sqlite] must be configured to use the SQLite datastore.")?;
+        Ok(Self::open(
+            &sqlite.path,
+            &config.db.traffic_collection_name,
+            &config.db.auth_collection_name,
+            &config.db.websocket_collection_name,
+        )?)
+    }
+
+    pub fn open(
+        path: &str,
+        traffic_table: &str,
+        auth_table: &str,
+        websocket_table: &str,
+    ) -> Result<Self, rusqlite::Error> {
+        let mut connection = Connection::open(path)?;
+        Self::migrate(&mut connection, traffic_table, auth_table, websocket_table)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            traffic_table: traffic_table.to_string(),
+            insert_traffic_sql: format!(
+                "INSERT INTO {} (method, scheme, host, path, query, request_headers, request_body,
+                    request_body_string, status, response_headers, response_body,
+                    response_body_string, version, request_body_ref, response_body_ref, captured_at,
+                    encrypted_fields, proxy_user, upstream_version, websocket_session,
+                    request_truncated, response_truncated)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                    ?18, ?19, ?20, ?21, ?22)",
+                traffic_table
+            ),
+            insert_auth_sql: format!(
+                "INSERT INTO {} (issuer, grant_type, client_id, redirect_url, scope)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (issuer, grant_type, client_id, redirect_url, scope) DO NOTHING",
+                auth_table
+            ),
+            websocket_table: websocket_table.to_string(),
+        })
+    }
+
+    fn migrate(
+        connection: &mut Connection,
+        traffic_table: &str,
+        auth_table: &str,
+        websocket_table: &str,
+    ) -> Result<(), rusqlite::Error> {
+        let migrations_table = format!("{}_migrations", traffic_table);
+        connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                version INTEGER PRIMARY KEY,
+                applied_at INTEGER NOT NULL DEFAULT (unixepoch())
+            )",
+            migrations_table
+        ))?;
+        let applied: i64 = connection.query_row(
+            &format!("SELECT COALESCE(MAX(version), 0) FROM {}", migrations_table),
+            [],
+            |row| row.get(0),
+        )?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            let version = (index + 1) as i64;
+            let sql = migration
+                .replace("{traffic}", traffic_table)
+                .replace("{auth}", auth_table)
+                .replace("{websocket}", websocket_table);
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(&sql)?;
+            transaction.execute(
+                &format!("INSERT INTO {} (version) VALUES (?1)", migrations_table),
+                [version],
+            )?;
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+
+    // Run a closure against the connection on the blocking thread pool.
+    async fn blocking<T, F>(&self, f: F) -> Result<T, rusqlite::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap()))
+            .await
+            .expect("SQLite task panicked.")
+    }
+
+    // Insert every entry in one transaction - a batch costs a single fsync.
+    pub async fn insert_traffic(&self, traffic: Vec<Traffic>) -> Result<(), rusqlite::Error> {
+        let sql = self.insert_traffic_sql.clone();
+        self.blocking(move |connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction.prepare_cached(&sql)?;
+                for traffic in &traffic {
+                    let captured_at = traffic
+                        .captured_at
+                        .unwrap_or_else(mongodb::bson::DateTime::now)
+                        .timestamp_millis();
+                    statement.execute(params![
+                        traffic.method,
+                        traffic.scheme,
+                        traffic.host,
+                        traffic.path,
+                        traffic.query,
+                        serde_json::to_string(&traffic.request_headers).unwrap(),
+                        traffic.request_body,
+                        traffic.request_body_string,
+                        traffic.status,
+                        serde_json::to_string(&traffic.response_headers).unwrap(),
+                        traffic.response_body,
+                        traffic.response_body_string,
+                        traffic.version,
+                        traffic
+                            .request_body_ref
+                            .as_ref()
+                            .map(|body_ref| serde_json::to_string(body_ref).unwrap()),
+                        traffic
+                            .response_body_ref
+                            .as_ref()
+                            .map(|body_ref| serde_json::to_string(body_ref).unwrap()),
+                        captured_at,
+                        serde_json::to_string(&traffic.encrypted_fields).unwrap(),
+                        traffic.proxy_user,
+                        traffic.upstream_version,
+                        traffic.websocket_session,
+                        traffic.request_truncated,
+                        traffic.response_truncated,
+                    ])?;
+                }
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    pub async fn expire_traffic(&self, policy: Policy) -> Result<u64, rusqlite::Error> {
+        let table = self.traffic_table.clone();
+        self.blocking(move |connection| {
+            let mut expired = 0;
+            if let Some(max_age) = policy.max_age {
+                expired += connection.execute(
+                    &format!("DELETE FROM {} WHERE captured_at < ?1", table),
+                    [cutoff_millis(max_age)],
+                )?;
+            }
+            for host_policy in &policy.hosts {
+                expired += connection.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE instr(host, ?1) > 0 AND captured_at < ?2",
+                        table
+                    ),
+                    params![host_policy.host, cutoff_millis(host_policy.max_age)],
+                )?;
+            }
+            if let Some(credentialed_max_age) = policy.credentialed_max_age {
+                let credentialed = CREDENTIAL_HEADERS
+                    .iter()
+                    .map(|header| format!("json_extract(request_headers, '$.\"{}\"') IS NOT NULL", header))
+                    .collect::<Vec<String>>()
+                    .join(" OR ");
+                expired += connection.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE ({}) AND captured_at < ?1",
+                        table, credentialed
+                    ),
+                    [cutoff_millis(credentialed_max_age)],
+                )?;
+            }
+            if let Some(max_documents) = policy.max_documents {
+                expired += connection.execute(
+                    &format!(
+                        "DELETE FROM {0} WHERE id <= (SELECT id FROM {0} ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+                        table
+                    ),
+                    [max_documents as i64],
+                )?;
+            }
+            Ok(expired as u64)
+        })
+        .await
+    }
+
+    // Sessions go by the age limits, from when they started, in one transaction with their frames.
+    pub async fn expire_websocket_sessions(&self, policy: Policy) -> Result<u64, rusqlite::Error> {
+        let table = self.websocket_table.clone();
+        self.blocking(move |connection| {
+            let transaction = connection.transaction()?;
+            let mut expired = 0;
+            if let Some(max_age) = policy.max_age {
+                expired += delete_sessions(
+                    &transaction,
+                    &table,
+                    "started_at < ?1",
+                    params![cutoff_millis(max_age)],
+                )?;
+            }
+            for host_policy in &policy.hosts {
+                expired += delete_sessions(
+                    &transaction,
+                    &table,
+                    "instr(host, ?1) > 0 AND started_at < ?2",
+                    params![host_policy.host, cutoff_millis(host_policy.max_age)],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(expired as u64)
+        })
+        .await
+    }
+
+    // The session and its frames in one transaction.
+    pub async fn insert_websocket_session(
+        &self,
+        session: WebSocketSession,
+    ) -> Result<(), rusqlite::Error> {
+        let table = self.websocket_table.clone();
+        self.blocking(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                &format!(
+                    "INSERT INTO {} (id, scheme, host, path, proxy_user, started_at, ended_at,
+                        encrypted_fields, truncated)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    table
+                ),
+                params![
+                    session.id,
+                    session.scheme,
+                    session.host,
+                    session.path,
+                    session.proxy_user,
+                    session.started_at.map(|at| at.timestamp_millis()),
+                    session.ended_at.map(|at| at.timestamp_millis()),
+                    serde_json::to_string(&session.encrypted_fields).unwrap(),
+                    session.truncated,
+                ],
+            )?;
+            {
+                let mut statement = transaction.prepare_cached(&format!(
+                    "INSERT INTO {}_messages (session_id, sequence, direction, opcode, fin, payload,
+                        payload_string, captured_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    table
+                ))?;
+                for (sequence, message) in session.messages.iter().enumerate() {
+                    statement.execute(params![
+                        session.id,
+                        sequence as i64,
+                        message.direction.as_str(),
+                        message.opcode,
+                        message.fin,
+                        message.payload,
+                        message.payload_string,
+                        message.captured_at.map(|at| at.timestamp_millis()),
+                    ])?;
+                }
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    pub async fn insert_auth(&self, auth: AuthInfo) -> Result<(), rusqlite::Error> {
+        let sql = self.insert_auth_sql.clone();
+        self.blocking(move |connection| {
+            connection.execute(
+                &sql,
+                params![
+                    auth.issuer,
+                    auth.grant_type,
+                    auth.client_id,
+                    auth.redirect_url,
+                    auth.scope,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+// Foreign keys aren't enforced unless asked for, so the frames are deleted first rather than cascaded.
+fn delete_sessions(
+    transaction: &rusqlite::Transaction,
+    table: &str,
+    condition: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<usize, rusqlite::Error> {
+    transaction.execute(
+        &format!(
+            "DELETE FROM {0}_messages WHERE session_id IN (SELECT id FROM {0} WHERE {1})",
+            table, condition
+        ),
+        params,
+    )?;
+    transaction.execute(
+        &format!("DELETE FROM {} WHERE {}", table, condition),
+        params,
+    )
+}
+
+fn cutoff_millis(max_age: std::time::Duration) -> i64 {
+    cutoff(max_age)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(sqlite: &Sqlite, text: &str) -> Vec<String> {
+        let connection = sqlite.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT path FROM traffic WHERE id IN
+                    (SELECT rowid FROM traffic_fts WHERE traffic_fts MATCH ?1) ORDER BY id",
+            )
+            .unwrap();
+        let paths = statement
+            .query_map([text], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_full_text_search() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("ohm-sqlite-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sqlite = Sqlite::open(
+            path.to_str().unwrap(),
+            "traffic",
+            "authinfo",
+            "websocket_sessions",
+        )?;
+        let [login, profile, reset] = [
+            ("/login", "invalid password"),
+            ("/profile", "{\"email\":\"user@foobar.com\"}"),
+            ("/reset", "password reset sent"),
+        ]
+        .map(|(path, body)| Traffic {
+            response_body: body.as_bytes().to_vec(),
+            response_body_string: Some(body.to_string()),
+            ..Traffic::fixture("foobar.com", path)
+        });
+        sqlite.add_traffic(&login).await?;
+        sqlite.add_traffic_batch(&[profile, reset]).await?;
+        assert_eq!(search(&sqlite, "password"), vec!["/login", "/reset"]);
+
+        // Expired rows leave the search index too.
+        let policy = Policy {
+            max_documents: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(sqlite.apply_retention(&policy).await?, 1);
+        assert_eq!(search(&sqlite, "password"), vec!["/reset"]);
+
+        // Opening an existing capture doesn't reapply migrations.
+        drop(sqlite);
+        let sqlite = Sqlite::open(
+            path.to_str().unwrap(),
+            "traffic",
+            "authinfo",
+            "websocket_sessions",
+        )?;
+        assert_eq!(search(&sqlite, "email"), vec!["/profile"]);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_expires_websocket_sessions() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::model::websocket::{Direction, WebSocketMessage, OPCODE_TEXT};
+        use mongodb::bson::DateTime;
+
+        let path =
+            std::env::temp_dir().join(format!("ohm-sqlite-sessions-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sqlite = Sqlite::open(
+            path.to_str().unwrap(),
+            "traffic",
+            "authinfo",
+            "websocket_sessions",
+        )?;
+        for (id, started_at) in [("old", DateTime::from_millis(0)), ("new", DateTime::now())] {
+            let mut session = WebSocketSession::new(&Traffic {
+                websocket_session: Some(id.to_string()),
+                ..Traffic::fixture("foobar.com", "/chat")
+            });
+            session.started_at = Some(started_at);
+            session.truncated = true;
+            session.messages = vec![WebSocketMessage::new(
+                Direction::ClientToServer,
+                OPCODE_TEXT,
+                true,
+                b"hello".to_vec(),
+            )];
+            sqlite.add_websocket_session(&session).await?;
+        }
+
+        let policy = Policy {
+            max_age: Some(std::time::Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(sqlite.apply_retention(&policy).await?, 1);
+        let connection = sqlite.connection.lock().unwrap();
+        let remaining: String = connection.query_row(
+            "SELECT group_concat(id) FROM websocket_sessions WHERE truncated = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(remaining, "new");
+        let messages: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM websocket_sessions_messages",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(messages, 1);
+        drop(connection);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,8 @@
+pub mod model;
+use crate::model::auth::AuthInfo;
+use crate::model::traffic::Traffic;
+
+pub mod data;
+
+pub mod service;
+pub use crate::service::builder::{ProxyBuilder, ProxyHandle};
@@ -1,66 +1,62 @@
-pub mod model;
-use crate::model::auth::AuthInfo;
-use crate::model::traffic::Traffic;
+use ohm::service::config::Config;
+use ohm::ProxyBuilder;
 
-pub mod data;
-use crate::data::mongo::Mongo;
-
-pub mod service;
-use crate::service::config::Config;
-use crate::service::filter::Filter;
-
-use std::convert::Infallible;
 use std::env;
-use std::net::SocketAddr;
-
-use hyper::service::{make_service_fn, service_fn};
-use hyper::Server;
-
-use once_cell::sync::OnceCell;
-
-static CONFIG: OnceCell<Config> = OnceCell::new();
-static DATASTORE_CLIENT: OnceCell<Mongo> = OnceCell::new();
-static FILTER_CHAIN: OnceCell<Filter> = OnceCell::new();
 
 #[tokio::main]
 async fn main() {
-    match CONFIG.set(Config::new(get_config_argument().await).await) {
-        Ok(()) => (),
-        Err(_e) => {
-            panic!("Error setting Config.");
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("decrypt") {
+        // ohm decrypt <path/to/key/file> < traffic.jsonl > decrypted.jsonl
+        // ohm decrypt <path/to/key/file> --blob <name> < bodies/ab/<name> > body
+        let key_path = args
+            .get(2)
+            .expect("Usage: ohm decrypt <path/to/key/file> [--blob <name>]");
+        let result = match (args.get(3).map(String::as_str), args.get(4)) {
+            (Some("--blob"), Some(address)) => {
+                ohm::data::encrypt::decrypt_blob_command(key_path, address)
+            }
+            (None, _) => ohm::data::encrypt::decrypt_command(key_path),
+            _ => panic!("Usage: ohm decrypt <path/to/key/file> [--blob <name>]"),
+        };
+        if let Err(e) = result {
+            eprintln!("[ERROR] [src/main.rs] [main]: (decrypt) {}", e);
+            std::process::exit(1);
         }
+        return;
     }
-    match DATASTORE_CLIENT.set(Mongo::new().await) {
-        Ok(()) => (),
-        Err(_e) => {
-            panic!("Error setting Mongo");
-        }
-    };
-    match FILTER_CHAIN.set(Filter::new().await) {
-        Ok(()) => (),
-        Err(_e) => {
-            panic!("Error setting Filter.");
-        }
-    };
+    let config = Config::new(get_config_argument().await).await;
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], CONFIG.get().unwrap().net.port));
-
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, Infallible>(service_fn(crate::service::proxy::handle_request))
-    });
-
-    let server = Server::bind(&addr)
-        .http1_preserve_header_case(true)
-        .http1_title_case_headers(true)
-        .serve(make_svc);
+    let mut proxy = match ProxyBuilder::from_config(config).start().await {
+        Ok(proxy) => proxy,
+        Err(e) => panic!("Error starting proxy: {}", e),
+    };
 
-    println!(
-        "[ohm] Serving on 127.0.0.1:{}...",
-        CONFIG.get().unwrap().net.port
-    );
+    for addr in proxy.addrs() {
+        println!("[ohm] Serving on {}...", addr);
+    }
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    // A listener that fails stops Ohm too, instead of leaving it running without it.
+    let mut failed = false;
+    tokio::select! {
+        _ = ohm::service::shutdown::signal() => {},
+        result = proxy.stopped() => {
+            if let Err(e) = result {
+                eprintln!("[ERROR] [src/main.rs] [main]: (listener stopped) {}", e);
+            }
+            failed = true;
+        }
+    }
+    println!("[ohm] Shutting down...");
+    match proxy.shutdown().await {
+        Ok(summary) => println!("[ohm] {}", summary),
+        Err(e) => {
+            eprintln!("server error: {}", e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
     }
 }
 
@@ -73,31 +69,7 @@ async fn get_config_argument() -> String {
         }
         2 => args[1].to_string(),
         _ => {
-            panic!("Usage: ohm [path/to/custom/config/file]");
+            panic!("Usage: ohm [path/to/custom/config/file] | ohm decrypt <path/to/key/file>");
         }
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    type Error = Box<dyn std::error::Error + Send + Sync>;
-
-    #[tokio::test]
-    async fn test_server_creation() -> Result<(), Error> {
-        let addr = SocketAddr::from(([127, 0, 0, 1], 8085));
-
-        let make_svc = make_service_fn(|_conn| async {
-            Ok::<_, Infallible>(service_fn(crate::service::proxy::handle_request))
-        });
-
-        let _ = Server::bind(&addr)
-            .http1_preserve_header_case(true)
-            .http1_title_case_headers(true)
-            .serve(make_svc);
-
-        // TODO: .await( ) -> Close it down.
-
-        Ok(())
-    }
-}
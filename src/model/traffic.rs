@@ -1,10 +1,11 @@
 use flate2::read::GzDecoder;
+use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
 use std::io::Read;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Traffic {
     pub method: String,
     pub scheme: String,
@@ -19,6 +20,47 @@ pub struct Traffic {
     pub response_body: Vec<u8>,
     pub response_body_string: Option<String>,
     pub version: String,
+    #[serde(default)]
+    pub request_body_ref: Option<BodyRef>,
+    #[serde(default)]
+    pub response_body_ref: Option<BodyRef>,
+    #[serde(default)]
+    pub captured_at: Option<DateTime>,
+    // Fields sealed by data::encrypt, e.g. "request_headers.cookie" or "response_body".
+    #[serde(default)]
+    pub encrypted_fields: Vec<String>,
+    // Who sent the request, when the proxy requires [proxy_auth].
+    #[serde(default)]
+    pub proxy_user: Option<String>,
+    // The protocol Ohm spoke to the server, which can differ from the client's version.
+    #[serde(default)]
+    pub upstream_version: Option<String>,
+    // The WebSocketSession this handshake opened, when the server switched protocols.
+    #[serde(default)]
+    pub websocket_session: Option<String>,
+    // A body is only recorded in part - it passed [net] max_body_bytes, or the exchange ended early.
+    #[serde(default)]
+    pub request_truncated: bool,
+    #[serde(default)]
+    pub response_truncated: bool,
+}
+
+pub fn version_name(version: hyper::Version) -> String {
+    match version {
+        hyper::Version::HTTP_2 => "HTTP/2.0".to_string(),
+        hyper::Version::HTTP_3 => "HTTP/3.0".to_string(),
+        hyper::Version::HTTP_10 => "HTTP/1.0".to_string(),
+        hyper::Version::HTTP_11 => "HTTP/1.1".to_string(),
+        _ => "HTTP/1.1".to_string(),
+    }
+}
+
+// A body kept outside the traffic document, stored once under the SHA-256 of its contents -
+// or with [encryption] bodies, an HMAC-SHA256 keyed with the data key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BodyRef {
+    pub sha256: String,
+    pub size: u64,
 }
 impl PartialEq for Traffic {
     fn eq(&self, other: &Self) -> bool {
@@ -32,9 +74,28 @@ impl PartialEq for Traffic {
             && (self.status == other.status)
             && (self.response_headers == other.response_headers)
             && (self.response_body == other.response_body)
+            && (self.request_body_ref == other.request_body_ref)
+            && (self.response_body_ref == other.response_body_ref)
     }
 }
 impl Eq for Traffic {}
+
+// A GET to https://{host}{path} answered with a 200 and no headers or bodies.
+// Tests set whatever else they need on top of it.
+#[cfg(test)]
+impl Traffic {
+    pub fn fixture(host: &str, path: &str) -> Self {
+        Self {
+            method: "GET".to_string(),
+            scheme: "https".to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            status: 200,
+            version: "HTTP/1.1".to_string(),
+            ..Default::default()
+        }
+    }
+}
 impl fmt::Display for Traffic {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.get_json())
@@ -61,13 +122,16 @@ impl Traffic {
             response_headers: HashMap::<std::string::String, std::string::String>::new(),
             response_body: Vec::<u8>::new(),
             response_body_string: None,
-            version: match request.version() {
-                hyper::Version::HTTP_2 => "HTTP/2.0".to_string(),
-                hyper::Version::HTTP_3 => "HTTP/3.0".to_string(),
-                hyper::Version::HTTP_10 => "HTTP/1.0".to_string(),
-                hyper::Version::HTTP_11 => "HTTP/1.1".to_string(),
-                _ => "HTTP/1.1".to_string(),
-            },
+            version: version_name(request.version()),
+            request_body_ref: None,
+            response_body_ref: None,
+            captured_at: Some(DateTime::now()),
+            encrypted_fields: Vec::new(),
+            proxy_user: None,
+            upstream_version: None,
+            websocket_session: None,
+            request_truncated: false,
+            response_truncated: false,
         };
         for (key, value) in request.headers() {
             me.request_headers.insert(
@@ -361,7 +425,8 @@ mod tests {
             ]
             .to_vec(),
             response_body_string: None,
-            version: "HTTP/1.1".to_string()
+            version: "HTTP/1.1".to_string(),
+            ..Default::default()
         };
         static ref TRAFFIC_TWO: Traffic = Traffic {
             method: "GET".to_string(),
@@ -386,7 +451,8 @@ mod tests {
             response_headers: HashMap::from([]),
             response_body: [].to_vec(),
             response_body_string: None,
-            version: "HTTP/1.1".to_string()
+            version: "HTTP/1.1".to_string(),
+            ..Default::default()
         };
         static ref TRAFFIC_THREE: Traffic = Traffic {
             method: "GET".to_string(),
@@ -411,7 +477,8 @@ mod tests {
             response_headers: HashMap::from([]),
             response_body: [].to_vec(),
             response_body_string: Some("PONG!".to_string()),
-            version: "HTTP/1.1".to_string()
+            version: "HTTP/1.1".to_string(),
+            ..Default::default()
         };
     }
 
@@ -429,4 +496,18 @@ mod tests {
         assert_eq!(TRAFFIC_ONE.get_json(), TRAFFIC_ONE.get_json());
         assert_eq!(TRAFFIC_TWO.get_json(), TRAFFIC_TWO.get_json());
     }
+
+    #[test]
+    fn test_captured_at_round_trip() {
+        let mut traffic = TRAFFIC_TWO.clone();
+        traffic.captured_at = Some(DateTime::from_millis(1672795690000));
+        let parsed: Traffic = serde_json::from_str(&traffic.get_json()).unwrap();
+        assert_eq!(parsed.captured_at, traffic.captured_at);
+
+        // Documents recorded before captured_at existed still parse.
+        let mut legacy: serde_json::Value = serde_json::from_str(&TRAFFIC_TWO.get_json()).unwrap();
+        legacy.as_object_mut().unwrap().remove("captured_at");
+        let parsed: Traffic = serde_json::from_value(legacy).unwrap();
+        assert_eq!(parsed.captured_at, None);
+    }
 }
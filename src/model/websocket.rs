@@ -0,0 +1,98 @@
+use crate::Traffic;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+pub const OPCODE_TEXT: u8 = 0x1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    // As serialized, for stores with a column per field.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ClientToServer => "client_to_server",
+            Self::ServerToClient => "server_to_client",
+        }
+    }
+}
+
+// One frame as it crossed the proxy. Fragmented messages are kept frame by frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebSocketMessage {
+    pub direction: Direction,
+    pub opcode: u8,
+    pub fin: bool,
+    // Unmasked.
+    pub payload: Vec<u8>,
+    pub payload_string: Option<String>,
+    pub captured_at: Option<DateTime>,
+}
+
+impl WebSocketMessage {
+    pub fn new(direction: Direction, opcode: u8, fin: bool, payload: Vec<u8>) -> Self {
+        let payload_string = match opcode {
+            OPCODE_TEXT => std::str::from_utf8(&payload).ok().map(str::to_string),
+            _ => None,
+        };
+        Self {
+            direction,
+            opcode,
+            fin,
+            payload,
+            payload_string,
+            captured_at: Some(DateTime::now()),
+        }
+    }
+
+    // What recording the frame costs, raw and decoded.
+    pub fn recorded_bytes(&self) -> usize {
+        self.payload.len() + self.payload_string.as_ref().map_or(0, String::len)
+    }
+}
+
+// The frames exchanged over an upgraded connection, stored once it closes.
+// The handshake is recorded as Traffic with websocket_session set to this id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebSocketSession {
+    pub id: String,
+    pub scheme: String,
+    pub host: String,
+    pub path: String,
+    #[serde(default)]
+    pub proxy_user: Option<String>,
+    pub started_at: Option<DateTime>,
+    pub ended_at: Option<DateTime>,
+    pub messages: Vec<WebSocketMessage>,
+    // Frames past [net] max_websocket_bytes were relayed but not recorded.
+    #[serde(default)]
+    pub truncated: bool,
+    // "messages.payload" when data::encrypt sealed the payloads.
+    #[serde(default)]
+    pub encrypted_fields: Vec<String>,
+}
+
+impl WebSocketSession {
+    pub fn new(handshake: &Traffic) -> Self {
+        Self {
+            id: handshake.websocket_session.clone().unwrap_or_default(),
+            scheme: handshake.scheme.clone(),
+            host: handshake.host.clone(),
+            path: handshake.path.clone(),
+            proxy_user: handshake.proxy_user.clone(),
+            started_at: Some(DateTime::now()),
+            ended_at: None,
+            messages: Vec::new(),
+            truncated: false,
+            encrypted_fields: Vec::new(),
+        }
+    }
+
+    pub fn get_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
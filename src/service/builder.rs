@@ -0,0 +1,587 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{join_all, select_all};
+use tokio::task::JoinHandle;
+use tokio_util::sync::DropGuard;
+
+use crate::data::Datastore;
+use crate::service::ca::CA;
+use crate::service::config::Config;
+use crate::service::listener::{ListenAddr, Listener};
+use crate::service::shutdown::Summary;
+use crate::service::state::{AppState, TrafficHook};
+use crate::Traffic;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// Start an Ohm proxy in-process.
+// Anything not set comes from the config: the [db] datastore, the [ca] files and [net] listeners.
+//
+//     let proxy = ProxyBuilder::new()
+//         .datastore(memory.clone())
+//         .start()
+//         .await?;
+//     // Point a client at proxy.addr(), then check what memory recorded.
+//     let summary = proxy.shutdown().await?;
+pub struct ProxyBuilder {
+    config: Config,
+    listeners: Option<Vec<Listener>>,
+    ca: Option<CA>,
+    // Generate a throwaway CA rather than read the [ca] files, unless one is given.
+    generate_ca: bool,
+    datastore: Option<Arc<dyn Datastore>>,
+    hooks: Vec<TrafficHook>,
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyBuilder {
+    // An in-memory datastore, no filtering, a throwaway CA and any free port on the loopback
+    // interface. Clients have to be told to trust the CA - see state().ca.cert_der().
+    pub fn new() -> Self {
+        Self {
+            generate_ca: true,
+            ..Self::from_config(Config::default())
+        }
+    }
+
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            listeners: None,
+            ca: None,
+            generate_ca: false,
+            datastore: None,
+            hooks: Vec::new(),
+        }
+    }
+
+    // Listen here instead of on the configured listeners. Call it again to add more.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listeners
+            .get_or_insert_with(Vec::new)
+            .push(Listener::tcp(addr));
+        self
+    }
+
+    pub fn listen_unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.listeners
+            .get_or_insert_with(Vec::new)
+            .push(Listener::unix(path));
+        self
+    }
+
+    // Any kind of listener, e.g. Listener::socks5(addr).
+    pub fn listener(mut self, listener: Listener) -> Self {
+        self.listeners.get_or_insert_with(Vec::new).push(listener);
+        self
+    }
+
+    pub fn ca(mut self, ca: CA) -> Self {
+        self.ca = Some(ca);
+        self
+    }
+
+    // The host allow/deny lists and identity providers the filter chain checks.
+    pub fn filter(mut self, filter: crate::service::config::Filter) -> Self {
+        self.config.filter = filter;
+        self
+    }
+
+    // Require Basic Proxy-Authorization from these users, and record who sent each request.
+    pub fn proxy_auth(mut self, proxy_auth: crate::service::config::ProxyAuth) -> Self {
+        self.config.proxy_auth = Some(proxy_auth);
+        self
+    }
+
+    // Send outbound requests through an HTTP or SOCKS5 egress proxy.
+    pub fn upstream(mut self, upstream: crate::service::config::Upstream) -> Self {
+        self.config.upstream = Some(upstream);
+        self
+    }
+
+    pub fn datastore(mut self, datastore: Arc<dyn Datastore>) -> Self {
+        self.datastore = Some(datastore);
+        self
+    }
+
+    // Run a hook on every piece of traffic that passes the filter chain, before it's stored.
+    pub fn on_traffic(mut self, hook: impl Fn(&Traffic) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    // Bind the listeners and start serving in the background.
+    pub async fn start(self) -> Result<ProxyHandle, Error> {
+        let listeners = match self.listeners {
+            Some(listeners) => listeners,
+            None => Listener::from_config(&self.config.net)?,
+        };
+        let datastore: Arc<dyn Datastore> = match self.datastore {
+            Some(datastore) => datastore,
+            None => crate::data::new_datastore(&self.config).await?.into(),
+        };
+        let ca = match self.ca {
+            Some(ca) => ca,
+            None if self.generate_ca => CA::generate()?,
+            None => CA::new(&self.config.ca).await?,
+        };
+        let mut state = AppState::new(self.config, datastore, ca).await?;
+        state.hooks = self.hooks;
+        let state = Arc::new(state);
+
+        if let Some(retention) = &state.config.retention {
+            // Stops when shutdown starts, so it doesn't keep the datastore alive.
+            crate::data::retention::spawn(
+                state.datastore.clone(),
+                crate::data::retention::Policy::from_config(retention),
+                std::time::Duration::from_secs(retention.interval_secs),
+                state.shutdown.draining.clone(),
+            )?;
+        }
+
+        // Stops whatever already started if a later listener fails to bind.
+        let guard = state.shutdown.draining.clone().drop_guard();
+        let mut addrs = Vec::new();
+        let mut servers = Vec::new();
+        for listener in &listeners {
+            let (addr, server) = listener.serve(state.clone()).await?;
+            addrs.push(addr);
+            servers.push(server);
+        }
+
+        Ok(ProxyHandle {
+            addrs,
+            state,
+            servers,
+            _guard: guard,
+        })
+    }
+}
+
+// A running proxy. Dropping the handle shuts it down too.
+pub struct ProxyHandle {
+    addrs: Vec<ListenAddr>,
+    state: Arc<AppState>,
+    servers: Vec<JoinHandle<Result<(), hyper::Error>>>,
+    _guard: DropGuard,
+}
+
+impl ProxyHandle {
+    // The first TCP address the proxy is listening on, with the real port when it was bound to port 0.
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs
+            .iter()
+            .find_map(|addr| match addr {
+                ListenAddr::Tcp(addr) => Some(*addr),
+                ListenAddr::Unix(_) => None,
+            })
+            .expect("The proxy has no TCP listener.")
+    }
+
+    // Every address the proxy is listening on, in the order the listeners were configured.
+    pub fn addrs(&self) -> &[ListenAddr] {
+        &self.addrs
+    }
+
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    // Stop accepting connections, drain open ones and CONNECT tunnels, wait for captured traffic
+    // to be filtered and stored, then flush the datastore. Each wait is bounded by
+    // [net] shutdown_timeout_secs, and whatever is still running after that is dropped.
+    pub async fn shutdown(self) -> Result<Summary, Error> {
+        let shutdown = &self.state.shutdown;
+        let timeout = Duration::from_secs(self.state.config.net.shutdown_timeout_secs);
+        shutdown.draining.cancel();
+        shutdown.tunnels.close();
+
+        let mut servers = self.servers;
+        let drained = tokio::time::timeout(timeout, async {
+            let results = join_all(servers.iter_mut()).await;
+            shutdown.tunnels.wait().await;
+            results
+        })
+        .await;
+        let mut tunnels_dropped = 0;
+        let served = match drained {
+            Ok(results) => Some(results),
+            Err(_) => {
+                tunnels_dropped = shutdown.tunnels.len();
+                shutdown.aborted.cancel();
+                for server in &servers {
+                    server.abort();
+                }
+                shutdown.tunnels.wait().await;
+                None
+            }
+        };
+        for addr in &self.addrs {
+            if let ListenAddr::Unix(path) = addr {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+
+        // Tunnels can't capture anything new now.
+        shutdown.pending.close();
+        let mut pending_dropped = 0;
+        if tokio::time::timeout(timeout, shutdown.pending.wait())
+            .await
+            .is_err()
+        {
+            pending_dropped = shutdown.pending.len();
+        }
+
+        if let Err(e) = self.state.datastore.flush().await {
+            eprintln!(
+                "[ERROR] [src/service/builder.rs] [shutdown]: (flushing the datastore) {}",
+                e
+            );
+        }
+
+        for result in served.unwrap_or_default() {
+            result??;
+        }
+        Ok(shutdown.summary(tunnels_dropped, pending_dropped))
+    }
+
+    // Resolves when any listener stops on its own, with the error it stopped on.
+    // The stopped listener is removed, so shutdown can still drain the others.
+    pub async fn stopped(&mut self) -> Result<(), Error> {
+        if self.servers.is_empty() {
+            return futures::future::pending().await;
+        }
+        let (result, index, _) = select_all(self.servers.iter_mut()).await;
+        self.servers.remove(index);
+        result??;
+        Ok(())
+    }
+
+    // Serve until every listener stops on its own.
+    pub async fn wait(self) -> Result<(), Error> {
+        let _guard = self._guard;
+        for result in join_all(self.servers).await {
+            result??;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A plain HTTP server that answers everything with PONG!
+    async fn upstream() -> SocketAddr {
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|_request| async {
+                Ok::<_, Infallible>(Response::new(Body::from("PONG!")))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_server_creation() -> Result<(), Error> {
+        let config = Config::new("./config/config.test.toml".to_string()).await;
+        let proxy = ProxyBuilder::from_config(config)
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .ca(CA::generate()?)
+            .datastore(Arc::new(Memory::new(10)))
+            .start()
+            .await?;
+        assert_ne!(proxy.addr().port(), 0);
+        proxy.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_defaults_generate_a_ca() -> Result<(), Error> {
+        let proxy = ProxyBuilder::new().start().await?;
+        assert!(!proxy.state().ca.cert_der()?.is_empty());
+        proxy.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bad_config_is_an_error() {
+        let mut config = Config::default();
+        config.db.kind = "nope".to_string();
+        let result = ProxyBuilder::from_config(config)
+            .ca(CA::generate().unwrap())
+            .start()
+            .await;
+        assert!(result.is_err());
+
+        let mut config = Config::default();
+        config.ca.pem_relative_path = "./does/not/exist.pem".to_string();
+        config.ca.key_relative_path = "./does/not/exist.key".to_string();
+        assert!(ProxyBuilder::from_config(config).start().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_retention() -> Result<(), Error> {
+        let retention = |interval_secs| crate::service::config::Retention {
+            interval_secs,
+            max_age_secs: Some(60),
+            max_documents: None,
+            credentialed_max_age_secs: None,
+            hosts: Vec::new(),
+        };
+        let config = Config {
+            retention: Some(retention(0)),
+            ..Config::default()
+        };
+        assert!(ProxyBuilder::from_config(config)
+            .ca(CA::generate()?)
+            .start()
+            .await
+            .is_err());
+
+        let memory = Arc::new(Memory::new(10));
+        let config = Config {
+            retention: Some(retention(1)),
+            ..Config::default()
+        };
+        let proxy = ProxyBuilder::from_config(config)
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+        proxy.shutdown().await?;
+        tokio::task::yield_now().await;
+        // Nothing is left holding the datastore.
+        assert_eq!(Arc::strong_count(&memory), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_records_proxied_traffic() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let memory = Arc::new(Memory::new(10));
+        let hooked = Arc::new(AtomicUsize::new(0));
+        let counter = hooked.clone();
+        let proxy = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .on_traffic(move |_traffic| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .start()
+            .await?;
+
+        let mut stream = tokio::net::TcpStream::connect(proxy.addr()).await?;
+        let request = format!(
+            "GET http://{0}/ping HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.ends_with("PONG!"));
+
+        for _ in 0..100 {
+            if !memory.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let recorded = memory.recent_traffic(1);
+        assert_eq!(recorded[0].path, "/ping");
+        assert_eq!(recorded[0].response_body_string, Some("PONG!".to_string()));
+        assert_eq!(recorded[0].upstream_version, Some("HTTP/1.1".to_string()));
+        assert_eq!(hooked.load(Ordering::SeqCst), 1);
+
+        proxy.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_auth() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let memory = Arc::new(Memory::new(10));
+        let proxy = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .proxy_auth(crate::service::config::ProxyAuth {
+                realm: "ohm".to_string(),
+                users: vec![crate::service::config::ProxyUser {
+                    username: "alice".to_string(),
+                    password: "hunter2".to_string(),
+                }],
+            })
+            .start()
+            .await?;
+
+        let send = |credentials: &str| {
+            let request = format!(
+                "GET http://{0}/ping HTTP/1.1\r\nHost: {0}\r\n{1}Connection: close\r\n\r\n",
+                upstream, credentials
+            );
+            let addr = proxy.addr();
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await?;
+                stream.write_all(request.as_bytes()).await?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await?;
+                Ok::<_, Error>(response)
+            }
+        };
+        let response = send("").await?;
+        assert!(response.starts_with("HTTP/1.1 407"));
+        assert!(response.contains("Proxy-Authenticate: Basic realm=\"ohm\""));
+
+        let credentials = format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            openssl::base64::encode_block(b"alice:hunter2")
+        );
+        let response = send(&credentials).await?;
+        assert!(response.ends_with("PONG!"));
+
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(10);
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].proxy_user, Some("alice".to_string()));
+        assert!(!recorded[0]
+            .request_headers
+            .contains_key("proxy-authorization"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let egress_memory = Arc::new(Memory::new(10));
+        let egress = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(egress_memory.clone())
+            .proxy_auth(crate::service::config::ProxyAuth {
+                realm: "egress".to_string(),
+                users: vec![crate::service::config::ProxyUser {
+                    username: "ohm".to_string(),
+                    password: "hunter2".to_string(),
+                }],
+            })
+            .start()
+            .await?;
+        let memory = Arc::new(Memory::new(10));
+        let proxy = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .upstream(crate::service::config::Upstream {
+                url: format!("http://{}", egress.addr()),
+                username: Some("ohm".to_string()),
+                password: Some("hunter2".to_string()),
+                no_proxy: Vec::new(),
+            })
+            .start()
+            .await?;
+
+        let mut stream = tokio::net::TcpStream::connect(proxy.addr()).await?;
+        let request = format!(
+            "GET http://{0}/ping HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.ends_with("PONG!"));
+
+        proxy.shutdown().await?;
+        egress.shutdown().await?;
+        // Ohm records the exchange without the credentials it added for the egress proxy.
+        let recorded = memory.recent_traffic(1);
+        assert!(!recorded[0]
+            .request_headers
+            .contains_key("proxy-authorization"));
+        let relayed = egress_memory.recent_traffic(1);
+        assert_eq!(relayed[0].path, "/ping");
+        assert_eq!(relayed[0].proxy_user, Some("ohm".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unix_listener() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let path = std::env::temp_dir().join(format!("ohm-listener-{}.sock", std::process::id()));
+        let memory = Arc::new(Memory::new(10));
+        let proxy = ProxyBuilder::new()
+            .listen_unix(&path)
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+        assert_eq!(proxy.addrs(), &[ListenAddr::Unix(path.clone())]);
+        let mode =
+            std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path)?.permissions());
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await?;
+        let request = format!(
+            "GET http://{0}/ping HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.ends_with("PONG!"));
+
+        let summary = proxy.shutdown().await?;
+        assert_eq!(summary.accepted, 1);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_batches() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let memory = Arc::new(Memory::new(10));
+        // A window long enough that only the shutdown flush can write the batch.
+        let batcher = crate::data::batch::Batcher::new(
+            Box::new(memory.clone()),
+            10,
+            10,
+            std::time::Duration::from_secs(60),
+            crate::data::batch::Backpressure::Block,
+            None,
+        )?;
+        let proxy = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(Arc::new(batcher))
+            .start()
+            .await?;
+
+        let mut stream = tokio::net::TcpStream::connect(proxy.addr()).await?;
+        let request = format!(
+            "GET http://{0}/ping HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(memory.is_empty());
+
+        let summary = proxy.shutdown().await?;
+        assert_eq!(summary.accepted, 1);
+        assert_eq!(summary.pending_dropped, 0);
+        assert_eq!(memory.recent_traffic(1)[0].path, "/ping");
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
-use http::uri::Authority;
-use hyper::{Body, Request};
+use std::net::IpAddr;
+
 use tokio_rustls::rustls;
 use tokio_rustls::rustls::ServerConfig;
 
@@ -8,7 +8,7 @@ use openssl::bn::BigNum;
 use openssl::hash::MessageDigest;
 use openssl::pkey::{PKey, Private};
 use openssl::rand;
-use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
 use openssl::x509::{X509Builder, X509NameBuilder, X509};
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -19,35 +19,30 @@ pub struct CA {
 }
 
 impl CA {
-    pub async fn new() -> Self {
-        let private_key_bytes: &[u8] =
-            &std::fs::read(&crate::CONFIG.get().unwrap().ca.key_relative_path).unwrap();
-        let pkey =
-            PKey::private_key_from_pem(private_key_bytes).expect("Failed to parse private key");
-        let ca_cert_bytes: &[u8] =
-            &std::fs::read(&crate::CONFIG.get().unwrap().ca.pem_relative_path).unwrap();
-        let cert = X509::from_pem(ca_cert_bytes).expect("Failed to parse CA certificate pem.");
-
-        Self {
+    pub async fn new(config: &crate::service::config::Ca) -> Result<Self, Error> {
+        let private_key_bytes: &[u8] = &std::fs::read(&config.key_relative_path)
+            .map_err(|e| format!("[ca] key {:?}: {}", config.key_relative_path, e))?;
+        let pkey = PKey::private_key_from_pem(private_key_bytes)
+            .map_err(|e| format!("Failed to parse private key: {}", e))?;
+        let ca_cert_bytes: &[u8] = &std::fs::read(&config.pem_relative_path)
+            .map_err(|e| format!("[ca] certificate {:?}: {}", config.pem_relative_path, e))?;
+        let cert = X509::from_pem(ca_cert_bytes)
+            .map_err(|e| format!("Failed to parse CA certificate pem: {}", e))?;
+
+        Ok(Self {
             ca_cert: cert,
             signing_key: pkey,
-        }
+        })
     }
 
-    pub async fn get_proxy_config(
-        &mut self,
-        request: Request<Body>,
-    ) -> Result<ServerConfig, Error> {
-        let authority = request
-            .uri()
-            .authority()
-            .expect("URI does not contain authority");
-
-        self.create_server_config(authority).await
+    // A server config presenting a certificate for the host, signed by this CA.
+    pub async fn get_host_config(&self, host: &str) -> Result<ServerConfig, Error> {
+        self.create_server_config(host.trim_matches(|c| c == '[' || c == ']'))
+            .await
     }
 
-    async fn create_server_config(&mut self, authority: &Authority) -> Result<ServerConfig, Error> {
-        let result = self.create_proxy_certificate(authority).await;
+    async fn create_server_config(&self, host: &str) -> Result<ServerConfig, Error> {
+        let result = self.create_proxy_certificate(host).await;
         let cert: rustls::Certificate = match result {
             Ok(t) => t,
             Err(e) => return Err(e),
@@ -74,17 +69,12 @@ impl CA {
         Ok(server_config)
     }
 
-    async fn create_proxy_certificate(
-        &mut self,
-        authority: &Authority,
-    ) -> Result<rustls::Certificate, Error> {
+    async fn create_proxy_certificate(&self, host: &str) -> Result<rustls::Certificate, Error> {
         let mut name_builder = X509NameBuilder::new()?;
         name_builder.append_entry_by_text("C", "US").unwrap();
         name_builder.append_entry_by_text("ST", "CA").unwrap();
         name_builder.append_entry_by_text("O", "OHM").unwrap();
-        name_builder
-            .append_entry_by_text("CN", authority.host())
-            .unwrap();
+        name_builder.append_entry_by_text("CN", host).unwrap();
         let name = name_builder.build();
 
         let mut x509_builder = X509Builder::new().unwrap();
@@ -99,9 +89,14 @@ impl CA {
         x509_builder.set_pubkey(&self.signing_key)?;
         x509_builder.set_issuer_name(self.ca_cert.subject_name())?;
 
-        let alternative_name = SubjectAlternativeName::new()
-            .dns(authority.host())
-            .build(&x509_builder.x509v3_context(Some(&self.ca_cert), None))?;
+        // Clients that connected to an IP address check for an IP SAN, not a DNS one.
+        let mut alternative_name = SubjectAlternativeName::new();
+        match host.parse::<IpAddr>() {
+            Ok(_) => alternative_name.ip(host),
+            Err(_) => alternative_name.dns(host),
+        };
+        let alternative_name =
+            alternative_name.build(&x509_builder.x509v3_context(Some(&self.ca_cert), None))?;
         x509_builder.append_extension(alternative_name)?;
 
         let mut serial_number = [0; 16];
@@ -115,4 +110,34 @@ impl CA {
 
         Ok(rustls::Certificate(x509.to_der()?))
     }
+
+    // The CA certificate clients need to trust, DER encoded.
+    pub fn cert_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.ca_cert.to_der()?)
+    }
+
+    // A throwaway self-signed CA for test harnesses - clients must be told to trust it.
+    pub fn generate() -> Result<Self, Error> {
+        let signing_key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048)?)?;
+        let mut name_builder = X509NameBuilder::new()?;
+        name_builder.append_entry_by_text("CN", "OHM Test CA")?;
+        let name = name_builder.build();
+
+        let mut x509_builder = X509Builder::new()?;
+        x509_builder.set_subject_name(&name)?;
+        x509_builder.set_issuer_name(&name)?;
+        x509_builder.set_version(2)?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        x509_builder.set_not_before(&not_before)?;
+        let not_after = Asn1Time::days_from_now(1)?;
+        x509_builder.set_not_after(&not_after)?;
+        x509_builder.set_pubkey(&signing_key)?;
+        x509_builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        x509_builder.sign(&signing_key, MessageDigest::sha256())?;
+
+        Ok(Self {
+            ca_cert: x509_builder.build(),
+            signing_key,
+        })
+    }
 }
@@ -6,11 +6,70 @@ pub struct Config {
     pub ca: Ca,
     pub db: Db,
     pub filter: Filter,
+    pub redis: Option<Redis>,
+    pub jsonl: Option<JsonLines>,
+    pub sqlite: Option<Sqlite>,
+    pub memory: Option<Memory>,
+    #[serde(default)]
+    pub sinks: Vec<Sink>,
+    pub spool: Option<Spool>,
+    pub batch: Option<Batch>,
+    pub bodies: Option<Bodies>,
+    pub retention: Option<Retention>,
+    pub encryption: Option<Encryption>,
+    pub proxy_auth: Option<ProxyAuth>,
+    pub upstream: Option<Upstream>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Net {
+    // A single listener on 127.0.0.1, used when no [[net.listeners]] are configured.
+    #[serde(default = "default_net_port")]
     pub port: u16,
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    // How long shutdown waits for open tunnels, and then for pending stores, before giving up.
+    #[serde(default = "default_net_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    // How much of each request and response body is recorded. Bodies stream through whole either way.
+    #[serde(default = "default_net_max_body_bytes")]
+    pub max_body_bytes: usize,
+    // How much of each WebSocket session's frames is recorded, counting text twice since it's also
+    // kept decoded. Sessions are stored as one document, so this keeps them under Mongo's 16MB.
+    #[serde(default = "default_net_max_websocket_bytes")]
+    pub max_websocket_bytes: usize,
+    // PEM CA certificates to trust for servers Ohm connects to, on top of the system roots.
+    pub upstream_ca_path: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Listener {
+    // An IPv4 or IPv6 address, or "unix:/path/to/socket".
+    pub address: String,
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default = "default_listener_mode")]
+    pub mode: String,
+    // Binding anything but a loopback address exposes the proxy - and what it records - to the network.
+    #[serde(default)]
+    pub allow_remote: bool,
+    // For mode = "reverse" - where requests go, by path prefix.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    // For mode = "reverse" - terminate TLS with this PEM certificate chain and key.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Route {
+    // Matches the path itself and anything below it, e.g. "/api" matches "/api/users".
+    pub prefix: String,
+    // The base URL requests are forwarded to, e.g. "http://127.0.0.1:3000/v1".
+    pub upstream: String,
+    // Drop the prefix before appending the request path to the upstream base URL.
+    #[serde(default)]
+    pub strip_prefix: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -21,20 +80,275 @@ pub struct Ca {
 
 #[derive(Serialize, Deserialize)]
 pub struct Db {
+    #[serde(default = "default_db_kind")]
+    pub kind: String,
     pub db_url: String,
     pub app_name: String,
     pub db_name: String,
     pub traffic_collection_name: String,
     pub auth_collection_name: String,
+    #[serde(default = "default_db_websocket_collection_name")]
+    pub websocket_collection_name: String,
+    #[serde(default = "default_db_indexes")]
+    pub indexes: Vec<String>,
+    #[serde(default = "default_db_text_index")]
+    pub text_index: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Redis {
+    pub url: String,
+    pub traffic_stream: String,
+    pub auth_stream: String,
+    #[serde(default = "default_redis_websocket_stream")]
+    pub websocket_stream: String,
+    pub max_len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JsonLines {
+    pub directory: String,
+    pub max_bytes: Option<u64>,
+    pub max_age_secs: Option<u64>,
+    #[serde(default)]
+    pub gzip: bool,
 }
 
 #[derive(Serialize, Deserialize)]
+pub struct Sqlite {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Memory {
+    pub capacity: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Sink {
+    pub name: Option<String>,
+    pub kind: String,
+    #[serde(default)]
+    pub allow_list_hosts: Vec<String>,
+    #[serde(default)]
+    pub deny_list_hosts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Spool {
+    pub directory: String,
+    pub max_bytes: u64,
+    #[serde(default = "default_spool_retry_initial_ms")]
+    pub retry_initial_ms: u64,
+    #[serde(default = "default_spool_retry_max_ms")]
+    pub retry_max_ms: u64,
+    #[serde(default = "default_spool_max_attempts")]
+    pub max_attempts: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Batch {
+    pub capacity: usize,
+    pub max_batch_size: usize,
+    pub window_ms: u64,
+    pub backpressure: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Bodies {
+    pub threshold_bytes: usize,
+    pub store: String,
+    #[serde(default)]
+    pub directory: String,
+    #[serde(default)]
+    pub collection_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Retention {
+    #[serde(default = "default_retention_interval_secs")]
+    pub interval_secs: u64,
+    pub max_age_secs: Option<u64>,
+    pub max_documents: Option<u64>,
+    pub credentialed_max_age_secs: Option<u64>,
+    #[serde(default)]
+    pub hosts: Vec<RetentionHost>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RetentionHost {
+    pub host: String,
+    pub max_age_secs: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Encryption {
+    pub key_path: String,
+    #[serde(default = "default_encryption_headers")]
+    pub headers: Vec<String>,
+    #[serde(default)]
+    pub bodies: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProxyAuth {
+    #[serde(default = "default_proxy_auth_realm")]
+    pub realm: String,
+    pub users: Vec<ProxyUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProxyUser {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Upstream {
+    // "http://host:port" for an HTTP proxy, or "socks5://host:port".
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Hosts reached directly - an entry matches the host and its subdomains, "*" matches everything.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Filter {
     pub allow_list_hosts: Vec<String>,
     pub deny_list_hosts: Vec<String>,
     pub identity_providers: Vec<String>,
 }
 
+fn default_net_port() -> u16 {
+    8085
+}
+
+fn default_listener_mode() -> String {
+    "forward".to_string()
+}
+
+fn default_net_shutdown_timeout_secs() -> u64 {
+    5
+}
+
+fn default_net_max_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_net_max_websocket_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_db_kind() -> String {
+    "mongo".to_string()
+}
+
+fn default_db_websocket_collection_name() -> String {
+    "websocket_sessions".to_string()
+}
+
+fn default_redis_websocket_stream() -> String {
+    "ohm:websocket".to_string()
+}
+
+fn default_db_indexes() -> Vec<String> {
+    [
+        "host",
+        "path",
+        "method",
+        "status",
+        "captured_at",
+        "proxy_user",
+    ]
+    .iter()
+    .map(|field| field.to_string())
+    .collect()
+}
+
+fn default_db_text_index() -> Vec<String> {
+    vec![
+        "request_body_string".to_string(),
+        "response_body_string".to_string(),
+    ]
+}
+
+fn default_spool_retry_initial_ms() -> u64 {
+    1000
+}
+
+fn default_spool_retry_max_ms() -> u64 {
+    60000
+}
+
+fn default_spool_max_attempts() -> u32 {
+    20
+}
+
+fn default_retention_interval_secs() -> u64 {
+    3600
+}
+
+fn default_proxy_auth_realm() -> String {
+    "ohm".to_string()
+}
+
+fn default_encryption_headers() -> Vec<String> {
+    ["cookie", "authorization", "set-cookie"]
+        .iter()
+        .map(|header| header.to_string())
+        .collect()
+}
+
+// A minimal config for embedding - an in-memory datastore and no filtering.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            net: Net {
+                port: 0,
+                listeners: Vec::new(),
+                shutdown_timeout_secs: default_net_shutdown_timeout_secs(),
+                max_body_bytes: default_net_max_body_bytes(),
+                max_websocket_bytes: default_net_max_websocket_bytes(),
+                upstream_ca_path: None,
+            },
+            ca: Ca {
+                pem_relative_path: String::new(),
+                key_relative_path: String::new(),
+            },
+            db: Db {
+                kind: "memory".to_string(),
+                db_url: String::new(),
+                app_name: "ohm".to_string(),
+                db_name: "ohm".to_string(),
+                traffic_collection_name: "traffic".to_string(),
+                auth_collection_name: "authinfo".to_string(),
+                websocket_collection_name: default_db_websocket_collection_name(),
+                indexes: default_db_indexes(),
+                text_index: default_db_text_index(),
+            },
+            filter: Filter {
+                allow_list_hosts: Vec::new(),
+                deny_list_hosts: Vec::new(),
+                identity_providers: Vec::new(),
+            },
+            redis: None,
+            jsonl: None,
+            sqlite: None,
+            memory: None,
+            sinks: Vec::new(),
+            spool: None,
+            batch: None,
+            bodies: None,
+            retention: None,
+            encryption: None,
+            proxy_auth: None,
+            upstream: None,
+        }
+    }
+}
+
 impl Config {
     pub async fn new(config_path: String) -> Self {
         let config_string = std::fs::read_to_string(config_path).unwrap();
@@ -44,6 +358,18 @@ impl Config {
             ca: config_toml.ca,
             db: config_toml.db,
             filter: config_toml.filter,
+            redis: config_toml.redis,
+            jsonl: config_toml.jsonl,
+            sqlite: config_toml.sqlite,
+            memory: config_toml.memory,
+            sinks: config_toml.sinks,
+            spool: config_toml.spool,
+            batch: config_toml.batch,
+            bodies: config_toml.bodies,
+            retention: config_toml.retention,
+            encryption: config_toml.encryption,
+            proxy_auth: config_toml.proxy_auth,
+            upstream: config_toml.upstream,
         }
     }
 }
@@ -0,0 +1,347 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::service::config;
+use crate::service::reverse::ReverseProxy;
+use crate::service::state::AppState;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// How a listener's clients talk to Ohm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // An HTTP proxy that clients are configured to use, with CONNECT for HTTPS.
+    Forward,
+    // A SOCKS5 proxy, for clients that only speak SOCKS.
+    Socks5,
+    // Connections redirected by iptables/nftables REDIRECT, from clients that can't be configured.
+    Transparent,
+    // A reverse proxy in front of the services in its routes.
+    Reverse,
+}
+
+impl Mode {
+    pub fn from_config(name: &str) -> Result<Self, Error> {
+        match name {
+            "forward" => Ok(Self::Forward),
+            "socks5" => Ok(Self::Socks5),
+            "transparent" => Ok(Self::Transparent),
+            "reverse" => Ok(Self::Reverse),
+            _ => Err(format!("Unknown [[net.listeners]] mode: {}", name).into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Listener {
+    pub addr: ListenAddr,
+    pub mode: Mode,
+    // The routes and TLS for Mode::Reverse.
+    pub reverse: Option<Arc<ReverseProxy>>,
+}
+
+impl Listener {
+    pub fn tcp(addr: SocketAddr) -> Self {
+        Self {
+            addr: ListenAddr::Tcp(addr),
+            mode: Mode::Forward,
+            reverse: None,
+        }
+    }
+
+    pub fn socks5(addr: SocketAddr) -> Self {
+        Self {
+            addr: ListenAddr::Tcp(addr),
+            mode: Mode::Socks5,
+            reverse: None,
+        }
+    }
+
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self {
+            addr: ListenAddr::Unix(path.into()),
+            mode: Mode::Forward,
+            reverse: None,
+        }
+    }
+
+    pub fn reverse(addr: SocketAddr, reverse: ReverseProxy) -> Self {
+        Self {
+            addr: ListenAddr::Tcp(addr),
+            mode: Mode::Reverse,
+            reverse: Some(Arc::new(reverse)),
+        }
+    }
+
+    // The [[net.listeners]], or a single loopback listener on [net] port when there are none.
+    pub fn from_config(net: &config::Net) -> Result<Vec<Self>, Error> {
+        if net.listeners.is_empty() {
+            return Ok(vec![Self::tcp(SocketAddr::from((
+                [127, 0, 0, 1],
+                net.port,
+            )))]);
+        }
+        net.listeners.iter().map(Self::from_listener).collect()
+    }
+
+    fn from_listener(listener: &config::Listener) -> Result<Self, Error> {
+        let mode = Mode::from_config(&listener.mode)?;
+        if let Some(path) = listener.address.strip_prefix("unix:") {
+            if mode != Mode::Forward {
+                return Err("Only forward listeners can use a Unix socket.".into());
+            }
+            return Ok(Self {
+                addr: ListenAddr::Unix(PathBuf::from(path)),
+                mode,
+                reverse: None,
+            });
+        }
+
+        // Accept IPv6 addresses with or without the brackets a URL would need.
+        let address = listener
+            .address
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let ip: IpAddr = match address.parse() {
+            Ok(ip) => ip,
+            Err(e) => {
+                return Err(format!(
+                    "Invalid [[net.listeners]] address {:?}: {}",
+                    listener.address, e
+                )
+                .into())
+            }
+        };
+        if !ip.is_loopback() {
+            if !listener.allow_remote {
+                return Err(format!(
+                    "Refusing to listen on {} - anyone who can reach it could proxy through Ohm \
+                     and read what it records. Set allow_remote = true on the listener to allow it.",
+                    ip
+                )
+                .into());
+            }
+            println!(
+                "[WARN] [src/service/listener.rs] [from_listener]: (allow_remote) Listening on {} - anyone who can reach it can proxy through Ohm.",
+                ip
+            );
+        }
+        let reverse = match mode {
+            Mode::Reverse => Some(Arc::new(ReverseProxy::from_config(listener)?)),
+            _ => None,
+        };
+        Ok(Self {
+            addr: ListenAddr::Tcp(SocketAddr::new(ip, listener.port)),
+            mode,
+            reverse,
+        })
+    }
+
+    // Bind and serve in the background until shutdown starts draining.
+    // Returns the bound address, with the real port when the listener asked for port 0.
+    pub async fn serve(
+        &self,
+        state: Arc<AppState>,
+    ) -> Result<(ListenAddr, JoinHandle<Result<(), hyper::Error>>), Error> {
+        match &self.addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let addr = listener.local_addr()?;
+                let server = match self.mode {
+                    Mode::Forward => spawn_server(state, AddrIncoming::from_listener(listener)?),
+                    Mode::Socks5 => spawn_streams(state, listener, |state, stream| {
+                        Box::pin(crate::service::socks::serve(state, stream))
+                    }),
+                    Mode::Transparent => spawn_streams(state, listener, |state, stream| {
+                        Box::pin(crate::service::transparent::serve(state, stream))
+                    }),
+                    Mode::Reverse => {
+                        let reverse = self
+                            .reverse
+                            .clone()
+                            .ok_or("Reverse listeners need a ReverseProxy.")?;
+                        spawn_streams(state, listener, move |state, stream| {
+                            Box::pin(reverse.clone().serve(state, stream))
+                        })
+                    }
+                };
+                Ok((ListenAddr::Tcp(addr), server))
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = tokio::net::UnixListener::bind(path)?;
+                // Only the user Ohm runs as can connect - anyone who can reads what it records.
+                std::fs::set_permissions(
+                    path,
+                    std::os::unix::fs::PermissionsExt::from_mode(0o600),
+                )?;
+                let incoming = hyper::server::accept::from_stream(futures::stream::unfold(
+                    listener,
+                    |listener| async move {
+                        let stream = listener.accept().await.map(|(stream, _)| stream);
+                        Some((stream, listener))
+                    },
+                ));
+                Ok((
+                    ListenAddr::Unix(path.clone()),
+                    spawn_server(state, incoming),
+                ))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err("Unix domain sockets need a Unix platform.".into()),
+        }
+    }
+}
+
+// A socket file left behind by a previous run would make binding the path fail.
+// Anything else at the path is left alone, and binding reports it.
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &std::path::Path) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+fn spawn_server<I>(state: Arc<AppState>, incoming: I) -> JoinHandle<Result<(), hyper::Error>>
+where
+    I: Accept + Send + 'static,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Error>,
+{
+    let draining = state.shutdown.draining.clone();
+    let make_svc = make_service_fn(move |_conn: &I::Conn| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                crate::service::proxy::handle_request(state.clone(), request)
+            }))
+        }
+    });
+    let server = Server::builder(incoming)
+        .http1_preserve_header_case(true)
+        .http1_title_case_headers(true)
+        .serve(make_svc);
+    tokio::spawn(server.with_graceful_shutdown(async move { draining.cancelled().await }))
+}
+
+// Accept connections until shutdown starts draining, passing each one to serve. Each one is
+// tracked like a CONNECT tunnel, so shutdown drains it and drops it after the timeout.
+fn spawn_streams<F>(
+    state: Arc<AppState>,
+    listener: TcpListener,
+    serve: F,
+) -> JoinHandle<Result<(), hyper::Error>>
+where
+    F: Fn(Arc<AppState>, TcpStream) -> BoxFuture<'static, Result<(), Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let draining = state.shutdown.draining.clone();
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        println!("[ERROR] [src/service/listener.rs] [spawn_streams]: (accept) {}", e);
+                        continue;
+                    }
+                },
+                _ = draining.cancelled() => return Ok(()),
+            };
+            let aborted = state.shutdown.aborted.clone();
+            let connection = serve(state.clone(), stream);
+            state.shutdown.tunnels.spawn(async move {
+                tokio::select! {
+                    result = connection => {
+                        if let Err(e) = result {
+                            if !e.to_string().starts_with("error shutting down connection") {
+                                println!("[ERROR] [src/service/listener.rs] [spawn_streams]: {:?}", e);
+                            }
+                        }
+                    },
+                    _ = aborted.cancelled() => {},
+                }
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(listeners: Vec<config::Listener>) -> config::Net {
+        config::Net {
+            port: 8085,
+            listeners,
+            shutdown_timeout_secs: 5,
+            max_body_bytes: 1024,
+            max_websocket_bytes: 1024,
+            upstream_ca_path: None,
+        }
+    }
+
+    fn listener(address: &str, allow_remote: bool) -> config::Listener {
+        config::Listener {
+            address: address.to_string(),
+            port: 8085,
+            mode: "forward".to_string(),
+            allow_remote,
+            routes: Vec::new(),
+            tls_cert_path: None,
+            tls_key_path: None,
+        }
+    }
+
+    #[test]
+    fn test_listeners_from_config() -> Result<(), Error> {
+        let listeners = Listener::from_config(&net(Vec::new()))?;
+        assert_eq!(
+            listeners[0].addr,
+            ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8085)))
+        );
+
+        let listeners = Listener::from_config(&net(vec![
+            listener("[::1]", false),
+            listener("unix:/tmp/ohm.sock", false),
+            listener("0.0.0.0", true),
+        ]))?;
+        assert_eq!(listeners[0].addr.to_string(), "[::1]:8085");
+        assert_eq!(listeners[1].addr.to_string(), "unix:/tmp/ohm.sock");
+        assert_eq!(listeners[2].addr.to_string(), "0.0.0.0:8085");
+
+        // Non-loopback interfaces have to be opted into.
+        assert!(Listener::from_config(&net(vec![listener("0.0.0.0", false)])).is_err());
+        assert!(Listener::from_config(&net(vec![listener("::", false)])).is_err());
+        Ok(())
+    }
+}
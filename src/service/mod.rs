@@ -1,4 +1,14 @@
+pub mod builder;
 pub mod ca;
 pub mod config;
 pub mod filter;
+pub mod listener;
 pub mod proxy;
+pub mod proxy_auth;
+pub mod reverse;
+pub mod shutdown;
+pub mod socks;
+pub mod state;
+pub mod transparent;
+pub mod upstream;
+pub mod websocket;
@@ -1,62 +1,94 @@
 use crate::data::Datastore;
 use crate::model::auth::AuthInfo;
-use crate::model::traffic::Traffic;
-use crate::service::ca::CA;
-use crate::DATASTORE_CLIENT;
-use crate::FILTER_CHAIN;
+use crate::model::traffic::{version_name, Traffic};
+use crate::service::state::AppState;
+use crate::service::upstream::UpstreamClient;
+use crate::service::websocket;
 
 use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 
+use futures::Stream;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
-use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri, Version};
 
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 
 use http::uri::{Authority, Scheme};
-use hyper_tls::HttpsConnector;
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::LazyConfigAcceptor;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
-pub async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+pub async fn handle_request(
+    state: Arc<AppState>,
+    mut request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let proxy_user = match &state.proxy_auth {
+        Some(proxy_auth) => match proxy_auth.authenticate(request.headers()) {
+            Some(user) => {
+                // The credentials are for Ohm - neither the upstream nor the datastore gets them.
+                request.headers_mut().remove(PROXY_AUTHORIZATION);
+                Some(user)
+            }
+            None => return Ok(proxy_auth.challenge()),
+        },
+        None => None,
+    };
+
     let response: Response<Body>;
     let result: Result<Response<Body>, Error> = if request.method() == Method::CONNECT {
-        handle_connect(request).await
+        handle_connect(state, request, proxy_user).await
     } else {
-        send_request(request).await
+        let client = state.client.clone();
+        send_request(state, &client, request, proxy_user).await
     };
     match result {
         Ok(t) => response = t,
-        Err(e) => {
-            response = Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(e.to_string()))
-                .unwrap();
-        }
+        Err(e) => response = bad_gateway(e),
     }
     Ok(response)
 }
 
-pub async fn handle_connect(mut request: Request<Body>) -> Result<Response<Body>, Error> {
-    if let Some(_addr) = request.uri().authority().map(|auth| auth.to_string()) {
-        tokio::task::spawn(async move {
+// What the client gets when the server couldn't be reached. Nothing is recorded for it.
+pub fn bad_gateway(e: Error) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from(e.to_string()))
+        .unwrap()
+}
+
+// Requests inside the tunnel don't carry Proxy-Authorization, so they're attributed to
+// whoever authenticated the CONNECT.
+pub async fn handle_connect(
+    state: Arc<AppState>,
+    mut request: Request<Body>,
+    proxy_user: Option<String>,
+) -> Result<Response<Body>, Error> {
+    if let Some(authority) = request.uri().authority().cloned() {
+        let tunnels = state.shutdown.tunnels.clone();
+        let aborted = state.shutdown.aborted.clone();
+        let tunnel = async move {
             match hyper::upgrade::on(&mut request).await {
                 Ok(upgraded) => {
-                    let mut ca = CA::new().await;
-                    let proxy_config = ca
-                        .get_proxy_config(request)
-                        .await
-                        .expect("Couldn't get proxy certificate.");
-                    let stream = match TlsAcceptor::from(Arc::new(proxy_config))
-                        .accept(upgraded)
-                        .await
-                    {
-                        Ok(stream) => stream,
-                        Err(_e) => return,
+                    let tunnel = Tunnel {
+                        client: state.client.clone(),
+                        destination: Destination {
+                            host: authority.host().to_string(),
+                            port: authority.port_u16().unwrap_or(443),
+                        },
+                        proxy_user,
                     };
-                    if let Err(e) = serve_stream(stream).await {
+                    if let Err(e) = intercept_tls(state, upgraded, tunnel).await {
                         if !e.to_string().starts_with("error shutting down connection") {
                             println!("[ERROR] [src/service/proxy.rs] [handle_connect]: (serve_stream error!) {:?}", e);
                         }
@@ -64,6 +96,13 @@ pub async fn handle_connect(mut request: Request<Body>) -> Result<Response<Body>
                 }
                 Err(e) => eprintln!("Upgrade error: {}", e),
             }
+        };
+        // Tracked so shutdown can drain it, and dropped if it outlives the shutdown timeout.
+        tunnels.spawn(async move {
+            tokio::select! {
+                _ = tunnel => {},
+                _ = aborted.cancelled() => {},
+            }
         });
         Ok(Response::new(Body::empty()))
     } else {
@@ -77,92 +116,239 @@ pub async fn handle_connect(mut request: Request<Body>) -> Result<Response<Body>
     }
 }
 
-// This function needs refactored - borrowed hudsucker's handling to get a proof-of-concept.
-// For proxying, must rewrite URI into absolute format - {SCHEME}://{AUTHORITY}/{URI}
-pub async fn serve_stream<I>(stream: I) -> Result<(), Error>
+// Where an intercepted connection was headed - the CONNECT or SOCKS target, or the original
+// destination of a redirected connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Destination {
+    // The authority to send a request on the connection to. The Host header names the server,
+    // on the destination's port unless it gives its own - the destination alone when it's missing.
+    fn authority(&self, scheme: &Scheme, host: Option<&HeaderValue>) -> Result<Authority, Error> {
+        let default_port = if *scheme == Scheme::HTTPS { 443 } else { 80 };
+        let host = match host {
+            Some(host) => Authority::try_from(host.as_bytes())?,
+            None if self.host.contains(':') => {
+                return Ok(format!("[{}]:{}", self.host, self.port).parse()?)
+            }
+            None => return Ok(format!("{}:{}", self.host, self.port).parse()?),
+        };
+        match host.port_u16() {
+            None if self.port != default_port => {
+                Ok(format!("{}:{}", host.host(), self.port).parse()?)
+            }
+            _ => Ok(host),
+        }
+    }
+}
+
+// How the requests on an intercepted connection are sent upstream, and who sent them.
+// Requests inside the tunnel don't carry Proxy-Authorization, so they're attributed to
+// whoever authenticated the CONNECT or SOCKS handshake.
+#[derive(Clone)]
+pub struct Tunnel {
+    pub client: UpstreamClient,
+    pub destination: Destination,
+    pub proxy_user: Option<String>,
+}
+
+// Intercept a raw TCP stream that should carry TLS or plaintext HTTP, e.g. from a SOCKS CONNECT.
+pub async fn intercept_stream(
+    state: Arc<AppState>,
+    stream: TcpStream,
+    tunnel: Tunnel,
+) -> Result<(), Error> {
+    let mut first = [0; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Ok(());
+    }
+    match first[0] {
+        // The first byte of a TLS handshake record.
+        0x16 => intercept_tls(state, stream, tunnel).await,
+        _ => serve_stream(state, stream, Scheme::HTTP, false, tunnel).await,
+    }
+}
+
+// Terminate TLS with a certificate minted for the client's SNI - or for the destination host
+// when it sends none - then proxy the HTTPS requests inside it.
+pub async fn intercept_tls<I>(
+    state: Arc<AppState>,
+    stream: I,
+    mut tunnel: Tunnel,
+) -> Result<(), Error>
 where
     I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let service = service_fn(|mut req| {
-        if req.version() == hyper::Version::HTTP_10 || req.version() == hyper::Version::HTTP_11 {
-            // Abstract this into another function - you need to do absolute URI rewriting.
-            let (mut parts, body) = req.into_parts();
-            let authority = parts
-                .headers
-                .get(hyper::header::HOST)
-                .expect("Host is a required header")
-                .as_bytes();
-            parts.uri = {
-                let mut parts = parts.uri.into_parts();
-                parts.scheme = Some(Scheme::HTTPS);
-                parts.authority =
-                    Some(Authority::try_from(authority).expect("Failed to parse authority"));
-                Uri::from_parts(parts).expect("Failed to build URI")
-            };
-            req = Request::from_parts(parts, body);
-        };
+    let handshake = match LazyConfigAcceptor::new(Acceptor::default(), stream).await {
+        Ok(handshake) => handshake,
+        Err(_e) => return Ok(()),
+    };
+    if let Some(server_name) = handshake.client_hello().server_name() {
+        // Names the server better than an address does, for requests without a Host header.
+        tunnel.destination.host = server_name.to_string();
+    }
+    let server_config = state.ca.get_host_config(&tunnel.destination.host).await?;
+    let stream = match handshake.into_stream(Arc::new(server_config)).await {
+        Ok(stream) => stream,
+        // Clients that don't trust the CA give up here - there is nothing to record.
+        Err(_e) => return Ok(()),
+    };
+    let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+    serve_stream(state, stream, Scheme::HTTPS, h2, tunnel).await
+}
 
-        send_request(req)
+// For proxying, must rewrite URI into absolute format - {SCHEME}://{AUTHORITY}/{URI}
+// HTTP/2 requests already carry the scheme and authority, and are served when ALPN picked h2.
+pub async fn serve_stream<I>(
+    state: Arc<AppState>,
+    stream: I,
+    scheme: Scheme,
+    h2: bool,
+    tunnel: Tunnel,
+) -> Result<(), Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let draining = state.shutdown.draining.clone();
+    let service = service_fn(move |req: Request<Body>| {
+        serve_request(state.clone(), tunnel.clone(), scheme.clone(), req)
     });
 
-    let result = Http::new()
+    let connection = Http::new()
+        .http2_only(h2)
         .serve_connection(stream, service)
-        .with_upgrades()
-        .await;
+        .with_upgrades();
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = draining.cancelled() => {
+            // Shutting down - finish the exchange in progress, then close the tunnel.
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
     match result {
         Ok(()) => Ok(()),
         Err(e) => Err(Box::new(e)),
     }
 }
 
-pub async fn send_request(request: Request<Body>) -> Result<Response<Body>, Error> {
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+// Complete the URI of a request read from an intercepted connection, then send it on.
+async fn serve_request(
+    state: Arc<AppState>,
+    tunnel: Tunnel,
+    scheme: Scheme,
+    mut request: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    if request.version() == Version::HTTP_10 || request.version() == Version::HTTP_11 {
+        let host = request.headers().get(hyper::header::HOST);
+        let authority = match tunnel.destination.authority(&scheme, host) {
+            Ok(authority) => authority,
+            Err(e) => {
+                let mut response = Response::new(Body::from(format!("Invalid Host header: {}", e)));
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                return Ok(response);
+            }
+        };
+        let mut parts = std::mem::take(request.uri_mut()).into_parts();
+        parts.scheme = Some(scheme);
+        parts.authority = Some(authority);
+        *request.uri_mut() = Uri::from_parts(parts)?;
+    }
+    match send_request(state, &tunnel.client, request, tunnel.proxy_user).await {
+        Ok(response) => Ok(response),
+        Err(e) => Ok(bad_gateway(e)),
+    }
+}
 
-    let (request_browser, request_traffic) = clone_request(request).await.unwrap();
+// Fails without recording anything when the server can't be reached.
+pub async fn send_request(
+    state: Arc<AppState>,
+    client: &UpstreamClient,
+    mut request: Request<Body>,
+    proxy_user: Option<String>,
+) -> Result<Response<Body>, Error> {
+    let max_body_bytes = state.config.net.max_body_bytes;
+    let client_upgrade = websocket::is_upgrade(&request).then(|| websocket::prepare(&mut request));
+    let (mut request_browser, request_traffic, request_body) =
+        clone_request(request, max_body_bytes).await.unwrap();
+    // The client picks HTTP/1.1 or HTTP/2 per upstream connection, but refuses to send an HTTP/2
+    // request over an HTTP/1.1 one - so leave the choice to it.
+    if request_browser.version() == Version::HTTP_2 {
+        *request_browser.version_mut() = Version::HTTP_11;
+    }
 
-    let result = client.request(request_browser).await;
-    let mut response = Response::default();
-    match result {
-        Ok(t) => {
-            response = t;
-        }
+    let mut response = match client.request(request_browser).await {
+        Ok(response) => response,
         Err(e) => {
             println!("[ERROR] [src/service/proxy.rs] [send_request]: {}", e);
+            return Err(Box::new(e));
         }
-    }
+    };
+    let upstream_version = Some(version_name(response.version()));
 
-    let (response_browser, response_traffic) = clone_response(response).await.unwrap();
+    let server_upgrade = match (&client_upgrade, response.status()) {
+        (Some(_), StatusCode::SWITCHING_PROTOCOLS) => Some(hyper::upgrade::on(&mut response)),
+        _ => None,
+    };
+
+    let (response_browser, response_traffic, response_body) =
+        clone_response(response, max_body_bytes).await.unwrap();
 
     let mut traffic = Traffic::new(request_traffic, response_traffic).await;
-    tokio::task::spawn(async move {
-        process_traffic(&mut traffic).await;
+    traffic.proxy_user = proxy_user;
+    traffic.upstream_version = upstream_version;
+    if let (Some(client), Some(server)) = (client_upgrade, server_upgrade) {
+        websocket::spawn_relay(state, traffic, client, server);
+        return Ok(response_browser);
+    }
+    let pending = state.shutdown.pending.clone();
+    pending.spawn(async move {
+        // Recorded once both bodies have streamed through.
+        let request_body = request_body.await.unwrap_or_default();
+        let response_body = response_body.await.unwrap_or_default();
+        traffic.request_body = request_body.bytes;
+        traffic.response_body = response_body.bytes;
+        traffic.request_truncated = request_body.truncated;
+        traffic.response_truncated = response_body.truncated;
+        process_traffic(&state, &mut traffic).await;
     });
     Ok(response_browser)
 }
 
-pub async fn process_traffic(traffic: &mut Traffic) {
-    let filter_chain = FILTER_CHAIN
-        .get()
-        .expect("Traffic filtering chain not intialized.");
-    if (filter_chain.filter(traffic).await).is_ok() {
-        store_traffic(traffic).await
-    }
+// Returns whether the traffic passed the filter chain.
+pub async fn process_traffic(state: &AppState, traffic: &mut Traffic) -> bool {
+    let passed = state.filter.filter(traffic).await.is_ok();
+    let counter = if passed {
+        for hook in &state.hooks {
+            hook(traffic);
+        }
+        match store_traffic(state.datastore.as_ref(), traffic).await {
+            Ok(()) => &state.shutdown.accepted,
+            Err(()) => &state.shutdown.failed,
+        }
+    } else {
+        &state.shutdown.filtered
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    passed
 }
 
-pub async fn store_traffic(traffic: &Traffic) {
-    let datastore = DATASTORE_CLIENT.get().expect("Datastore not initialized.");
+pub async fn store_traffic(datastore: &dyn Datastore, traffic: &Traffic) -> Result<(), ()> {
     let result = datastore.add_traffic(traffic).await;
     match result {
-        Ok(()) => {}
+        Ok(()) => Ok(()),
         Err(e) => {
             println!("[ERROR] [src/service/proxy.rs] [store_traffic]: {:?}", e);
+            Err(())
         }
     }
 }
 
-pub async fn store_auth(auth: &AuthInfo) {
-    let datastore = DATASTORE_CLIENT.get().expect("Datastore not initialized.");
+pub async fn store_auth(datastore: &dyn Datastore, auth: &AuthInfo) {
     let result = datastore.add_authinfo(auth).await;
     match result {
         Ok(()) => {}
@@ -174,12 +360,14 @@ pub async fn store_auth(auth: &AuthInfo) {
 
 // TODO: Implement .Copy() for hyper::traffic or find a better way.
 // "parts.extensions" is not cloned because it doesn't implement the trait and is left out here.
+// The first copy streams the body on, the second has none - the recorded body arrives on the receiver.
 
 pub async fn clone_request(
     request: Request<Body>,
-) -> Result<(Request<Body>, Request<Body>), Error> {
+    max_body_bytes: usize,
+) -> Result<(Request<Body>, Request<Body>, oneshot::Receiver<Captured>), Error> {
     let (parts, body) = request.into_parts();
-    let body_bytes = hyper::body::to_bytes(body).await?;
+    let (body, captured) = tee(body, max_body_bytes);
 
     let mut req1 = Request::builder()
         .uri(parts.uri.clone())
@@ -189,7 +377,7 @@ pub async fn clone_request(
         let headers = req1.headers_mut().unwrap();
         headers.extend(parts.headers.clone());
     }
-    let req1 = req1.body(Body::from(body_bytes.clone()))?;
+    let req1 = req1.body(body)?;
 
     let mut req2 = Request::builder()
         .uri(parts.uri.clone())
@@ -199,16 +387,17 @@ pub async fn clone_request(
         let headers = req2.headers_mut().unwrap();
         headers.extend(parts.headers.clone());
     }
-    let req2 = req2.body(Body::from(body_bytes.clone()))?;
+    let req2 = req2.body(Body::empty())?;
 
-    Ok((req1, req2))
+    Ok((req1, req2, captured))
 }
 
 pub async fn clone_response(
     response: Response<Body>,
-) -> Result<(Response<Body>, Response<Body>), Error> {
+    max_body_bytes: usize,
+) -> Result<(Response<Body>, Response<Body>, oneshot::Receiver<Captured>), Error> {
     let (parts, body) = response.into_parts();
-    let body_bytes = hyper::body::to_bytes(body).await?;
+    let (body, captured) = tee(body, max_body_bytes);
 
     let mut res1 = Response::builder()
         .status(parts.status)
@@ -217,7 +406,7 @@ pub async fn clone_response(
         let headers = res1.headers_mut().unwrap();
         headers.extend(parts.headers.clone());
     }
-    let res1 = res1.body(Body::from(body_bytes.clone()))?;
+    let res1 = res1.body(body)?;
 
     let mut res2 = Response::builder()
         .status(parts.status)
@@ -226,7 +415,211 @@ pub async fn clone_response(
         let headers = res2.headers_mut().unwrap();
         headers.extend(parts.headers.clone());
     }
-    let res2 = res2.body(Body::from(body_bytes.clone()))?;
+    let res2 = res2.body(Body::empty())?;
 
-    Ok((res1, res2))
+    Ok((res1, res2, captured))
+}
+
+// What the recorder keeps of a body that streamed through.
+#[derive(Debug, Default)]
+pub struct Captured {
+    pub bytes: Vec<u8>,
+    pub truncated: bool,
+}
+
+// Passes a body on chunk by chunk, keeping the first max_bytes of it for the recorder.
+// Downloads and event streams reach the client as they arrive instead of once they finish.
+pub fn tee(body: Body, max_bytes: usize) -> (Body, oneshot::Receiver<Captured>) {
+    let (sender, receiver) = oneshot::channel();
+    if body.is_end_stream() {
+        // Left as it is, so hyper still knows there's no body to send.
+        let _ = sender.send(Captured::default());
+        return (body, receiver);
+    }
+    let tee = Tee {
+        body,
+        captured: Captured::default(),
+        max_bytes,
+        sender: Some(sender),
+    };
+    (Body::wrap_stream(tee), receiver)
+}
+
+struct Tee {
+    body: Body,
+    captured: Captured,
+    max_bytes: usize,
+    sender: Option<oneshot::Sender<Captured>>,
+}
+
+impl Tee {
+    fn finish(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(std::mem::take(&mut self.captured));
+        }
+    }
+}
+
+impl Stream for Tee {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_data(cx);
+        match &poll {
+            // Already handed to the recorder - the rest only passes through.
+            Poll::Ready(Some(Ok(_))) if self.sender.is_none() => {}
+            Poll::Ready(Some(Ok(chunk))) => {
+                let room = self.max_bytes.saturating_sub(self.captured.bytes.len());
+                let kept = chunk.len().min(room);
+                self.captured.bytes.extend_from_slice(&chunk[..kept]);
+                if chunk.len() > room {
+                    // Recorded now rather than when the body ends, which an event stream may never do.
+                    self.captured.truncated = true;
+                    self.finish();
+                } else if self.body.is_end_stream() {
+                    // A body with a Content-Length isn't polled again once it's all been read.
+                    self.finish();
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Drop for Tee {
+    // Dropped before the end - the client went away or the exchange failed part way.
+    fn drop(&mut self) {
+        if !self.body.is_end_stream() {
+            self.captured.truncated = true;
+        }
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::service::ca::CA;
+    use crate::service::config::Config;
+    use crate::ProxyBuilder;
+    use hyper::service::make_service_fn;
+    use hyper::Server;
+    use std::net::SocketAddr;
+    use tokio::sync::Notify;
+
+    // Echoes request bodies, and serves /events as two events with a pause until release is notified.
+    async fn upstream(release: Arc<Notify>) -> SocketAddr {
+        let make_svc = make_service_fn(move |_conn| {
+            let release = release.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let release = release.clone();
+                    async move {
+                        if request.uri().path() != "/events" {
+                            return Ok::<_, Infallible>(Response::new(request.into_body()));
+                        }
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            sender.send_data("data: 1\n\n".into()).await.unwrap();
+                            release.notified().await;
+                            sender.send_data("data: 2\n\n".into()).await.unwrap();
+                        });
+                        Ok::<_, Infallible>(Response::new(body))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_tee() -> Result<(), Error> {
+        let chunks: Vec<Result<&str, Error>> = vec![Ok("hello "), Ok("streaming "), Ok("world")];
+        let (body, captured) = tee(Body::wrap_stream(futures::stream::iter(chunks)), 8);
+        assert_eq!(hyper::body::to_bytes(body).await?, "hello streaming world");
+        let captured = captured.await?;
+        assert_eq!(captured.bytes, b"hello st");
+        assert!(captured.truncated);
+
+        // Recorded as soon as it passes the limit, while the body is still streaming.
+        let (mut sender, body) = Body::channel();
+        let (mut body, captured) = tee(body, 8);
+        sender.send_data("data: 1\n\ndata: 2\n\n".into()).await?;
+        assert_eq!(body.data().await.unwrap()?, "data: 1\n\ndata: 2\n\n");
+        let captured = captured.await?;
+        assert_eq!(captured.bytes, b"data: 1\n");
+        assert!(captured.truncated);
+        sender.send_data("data: 3\n\n".into()).await?;
+        assert_eq!(body.data().await.unwrap()?, "data: 3\n\n");
+
+        let (body, captured) = tee(Body::from("whole"), 8);
+        assert_eq!(hyper::body::to_bytes(body).await?, "whole");
+        assert_eq!(captured.await?.bytes, b"whole");
+
+        // Dropped part way, e.g. when the client disconnects.
+        let (body, captured) = tee(Body::from("abandoned"), 8);
+        drop(body);
+        assert!(captured.await?.truncated);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streams_bodies() -> Result<(), Error> {
+        let release = Arc::new(Notify::new());
+        let upstream = upstream(release.clone()).await;
+        let memory = Arc::new(Memory::new(10));
+        let mut config = Config::default();
+        config.net.max_body_bytes = 12;
+        let proxy = ProxyBuilder::from_config(config)
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        let stream = TcpStream::connect(proxy.addr()).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+
+        let request = Request::post(format!("http://{}/echo", upstream))
+            .header(hyper::header::HOST, upstream.to_string())
+            .body(Body::from("ping"))?;
+        let response = sender.send_request(request).await?;
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "ping");
+
+        // The first event arrives while the upstream is still holding the second back.
+        let request = Request::get(format!("http://{}/events", upstream))
+            .header(hyper::header::HOST, upstream.to_string())
+            .body(Body::empty())?;
+        let mut body = sender.send_request(request).await?.into_body();
+        assert_eq!(body.data().await.unwrap()?, "data: 1\n\n");
+        release.notify_one();
+        assert_eq!(body.data().await.unwrap()?, "data: 2\n\n");
+        assert!(body.data().await.is_none());
+
+        drop(sender);
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(2);
+        let echo = recorded
+            .iter()
+            .find(|traffic| traffic.path == "/echo")
+            .unwrap();
+        assert_eq!(echo.request_body, b"ping");
+        assert_eq!(echo.response_body_string, Some("ping".to_string()));
+        assert!(!echo.request_truncated);
+        assert!(!echo.response_truncated);
+        let events = recorded
+            .iter()
+            .find(|traffic| traffic.path == "/events")
+            .unwrap();
+        assert_eq!(events.response_body, b"data: 1\n\ndat");
+        assert!(!events.request_truncated);
+        assert!(events.response_truncated);
+        Ok(())
+    }
 }
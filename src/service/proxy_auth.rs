@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use hyper::header::{HeaderMap, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use hyper::{Body, Response, StatusCode};
+
+use crate::service::config;
+
+// Basic Proxy-Authorization against the [proxy_auth] users, so traffic can be attributed to them.
+pub struct ProxyAuth {
+    realm: String,
+    users: HashMap<String, String>,
+}
+
+impl ProxyAuth {
+    pub fn new(config: &config::ProxyAuth) -> Self {
+        Self {
+            realm: config.realm.clone(),
+            users: config
+                .users
+                .iter()
+                .map(|user| (user.username.clone(), user.password.clone()))
+                .collect(),
+        }
+    }
+
+    // The user named by a request's Proxy-Authorization header, if the password matches.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers.get(PROXY_AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = openssl::base64::decode_block(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        match self.check(username, password) {
+            true => Some(username.to_string()),
+            false => None,
+        }
+    }
+
+    // Whether the password is right for the user, for protocols that send them as is.
+    pub fn check(&self, username: &str, password: &str) -> bool {
+        // Compared in constant time, so response timing doesn't give the password away.
+        self.users.get(username).is_some_and(|expected| {
+            expected.len() == password.len()
+                && openssl::memcmp::eq(expected.as_bytes(), password.as_bytes())
+        })
+    }
+
+    // Ask the client to retry with credentials.
+    pub fn challenge(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header(
+                PROXY_AUTHENTICATE,
+                format!("Basic realm=\"{}\"", self.realm),
+            )
+            .body(Body::from("Proxy authentication required."))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!(
+            "Basic {}",
+            openssl::base64::encode_block(credentials.as_bytes())
+        );
+        headers.insert(PROXY_AUTHORIZATION, HeaderValue::from_str(&value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let proxy_auth = ProxyAuth::new(&config::ProxyAuth {
+            realm: "ohm".to_string(),
+            users: vec![config::ProxyUser {
+                username: "alice".to_string(),
+                password: "hunter2:with:colons".to_string(),
+            }],
+        });
+        assert_eq!(
+            proxy_auth.authenticate(&headers("alice:hunter2:with:colons")),
+            Some("alice".to_string())
+        );
+        assert_eq!(proxy_auth.authenticate(&headers("alice:hunter3")), None);
+        assert_eq!(
+            proxy_auth.authenticate(&headers("bob:hunter2:with:colons")),
+            None
+        );
+        assert_eq!(proxy_auth.authenticate(&HeaderMap::new()), None);
+        assert_eq!(
+            proxy_auth.challenge().status(),
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+    }
+}
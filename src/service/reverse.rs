@@ -0,0 +1,474 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+
+use http::uri::Scheme;
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+use crate::service::config;
+use crate::service::proxy::{bad_gateway, send_request};
+use crate::service::state::AppState;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+struct Route {
+    prefix: String,
+    upstream: Uri,
+    strip_prefix: bool,
+}
+
+impl Route {
+    fn new(route: &config::Route) -> Result<Self, Error> {
+        if !route.prefix.starts_with('/') {
+            return Err(format!("Route prefix {:?} must start with /", route.prefix).into());
+        }
+        let upstream: Uri = match route.upstream.parse() {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                return Err(format!("Invalid route upstream {:?}: {}", route.upstream, e).into())
+            }
+        };
+        match (upstream.scheme_str(), upstream.authority()) {
+            (Some("http") | Some("https"), Some(_)) => {}
+            _ => {
+                return Err(format!(
+                    "Route upstream {:?} must be an http:// or https:// URL",
+                    route.upstream
+                )
+                .into())
+            }
+        }
+        Ok(Self {
+            prefix: route.prefix.clone(),
+            upstream,
+            strip_prefix: route.strip_prefix,
+        })
+    }
+
+    // "/api" matches "/api" and "/api/users", but not "/apiary".
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    // The request URI moved onto the upstream base URL, keeping the query.
+    fn rewrite(&self, uri: &Uri) -> Result<Uri, Error> {
+        let path = match self.strip_prefix {
+            true => &uri.path()[self.prefix.len()..],
+            false => uri.path(),
+        };
+        let mut path_and_query = format!(
+            "{}/{}",
+            self.upstream.path().trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        if let Some(query) = uri.query() {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+        let mut parts = self.upstream.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse()?);
+        Ok(Uri::from_parts(parts)?)
+    }
+}
+
+// Forwards requests to the services behind it, so Ohm can sit in front of a service under test
+// rather than be configured as the clients' proxy.
+pub struct ReverseProxy {
+    // Longest prefix first, so the most specific route wins.
+    routes: Vec<Route>,
+    tls: Option<TlsAcceptor>,
+}
+
+impl fmt::Debug for ReverseProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReverseProxy")
+            .field(
+                "routes",
+                &self
+                    .routes
+                    .iter()
+                    .map(|route| (&route.prefix, route.upstream.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl ReverseProxy {
+    pub fn new(routes: &[config::Route]) -> Result<Self, Error> {
+        if routes.is_empty() {
+            return Err("Reverse listeners need at least one [[net.listeners.routes]].".into());
+        }
+        let mut routes = routes
+            .iter()
+            .map(Route::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        Ok(Self { routes, tls: None })
+    }
+
+    pub fn from_config(listener: &config::Listener) -> Result<Self, Error> {
+        let proxy = Self::new(&listener.routes)?;
+        match (&listener.tls_cert_path, &listener.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => proxy.with_tls(cert_path, key_path),
+            (None, None) => Ok(proxy),
+            _ => Err("Reverse listeners need both tls_cert_path and tls_key_path for TLS.".into()),
+        }
+    }
+
+    // Terminate TLS with a PEM certificate chain and private key, e.g. the service's own.
+    pub fn with_tls(mut self, cert_path: &str, key_path: &str) -> Result<Self, Error> {
+        let certs = openssl::x509::X509::stack_from_pem(&std::fs::read(cert_path)?)?
+            .iter()
+            .map(|cert| Ok(rustls::Certificate(cert.to_der()?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let key = openssl::pkey::PKey::private_key_from_pem(&std::fs::read(key_path)?)?;
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, rustls::PrivateKey(key.private_key_to_pkcs8()?))?;
+        server_config.alpn_protocols = vec![
+            #[cfg(feature = "http2")]
+            b"h2".to_vec(),
+            b"http/1.1".to_vec(),
+        ];
+        self.tls = Some(TlsAcceptor::from(Arc::new(server_config)));
+        Ok(self)
+    }
+
+    fn route(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(path))
+    }
+
+    // Serve one accepted connection, terminating TLS first when it's configured.
+    pub async fn serve(
+        self: Arc<Self>,
+        state: Arc<AppState>,
+        stream: TcpStream,
+    ) -> Result<(), Error> {
+        match self.tls.clone() {
+            Some(acceptor) => {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    // A client that gave up on the handshake sent nothing to record.
+                    Err(_e) => return Ok(()),
+                };
+                self.serve_connection(state, stream, Scheme::HTTPS).await
+            }
+            None => self.serve_connection(state, stream, Scheme::HTTP).await,
+        }
+    }
+
+    async fn serve_connection<I>(
+        self: Arc<Self>,
+        state: Arc<AppState>,
+        stream: I,
+        scheme: Scheme,
+    ) -> Result<(), Error>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let draining = state.shutdown.draining.clone();
+        let service = service_fn(move |request| {
+            handle_request(state.clone(), self.clone(), request, scheme.clone())
+        });
+        let connection = Http::new()
+            .http1_preserve_header_case(true)
+            .http1_title_case_headers(true)
+            .serve_connection(stream, service)
+            .with_upgrades();
+        tokio::pin!(connection);
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            _ = draining.cancelled() => {
+                // Shutting down - finish the exchange in progress, then close the connection.
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+// Reverse proxy clients are the service's own clients, so [proxy_auth] doesn't apply.
+async fn handle_request(
+    state: Arc<AppState>,
+    proxy: Arc<ReverseProxy>,
+    request: Request<Body>,
+    scheme: Scheme,
+) -> Result<Response<Body>, Infallible> {
+    let route = match proxy.route(request.uri().path()) {
+        Some(route) => route,
+        None => {
+            let mut response =
+                Response::new(Body::from(format!("No route for {}", request.uri().path())));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+    };
+
+    match forward(state, route, request, scheme).await {
+        Ok(response) => Ok(response),
+        Err(e) => Ok(bad_gateway(e)),
+    }
+}
+
+async fn forward(
+    state: Arc<AppState>,
+    route: &Route,
+    mut request: Request<Body>,
+    scheme: Scheme,
+) -> Result<Response<Body>, Error> {
+    let uri = route.rewrite(request.uri())?;
+    // HTTP/2 clients send the host they asked for as the :authority rather than a Host header.
+    let authority = request
+        .uri()
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok());
+    let headers = request.headers_mut();
+    // The upstream sees its own host, and the one the client asked for in X-Forwarded-Host.
+    if let Some(host) = headers.remove(HOST).or(authority) {
+        headers.insert(X_FORWARDED_HOST, host);
+    }
+    if let Some(authority) = uri.authority() {
+        headers.insert(HOST, HeaderValue::from_str(authority.as_str())?);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_str(scheme.as_str())?);
+    *request.uri_mut() = uri;
+    let client = state.direct_client.clone();
+    send_request(state, &client, request, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::service::ca::CA;
+    use crate::service::listener::Listener;
+    use crate::ProxyBuilder;
+    use hyper::service::make_service_fn;
+    use hyper::Server;
+    use std::net::SocketAddr;
+
+    fn route(prefix: &str, upstream: &str, strip_prefix: bool) -> config::Route {
+        config::Route {
+            prefix: prefix.to_string(),
+            upstream: upstream.to_string(),
+            strip_prefix,
+        }
+    }
+
+    // Echoes its name, the path it was asked for and the Host it was sent.
+    async fn upstream(name: &'static str) -> SocketAddr {
+        let make_svc = make_service_fn(move |_conn| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+                let host = request.headers().get(HOST).unwrap().to_str().unwrap();
+                Ok::<_, Infallible>(Response::new(Body::from(format!(
+                    "{} {} {}",
+                    name,
+                    request.uri(),
+                    host
+                ))))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    // A self-signed certificate for localhost, written out as PEM like a supplied one would be.
+    fn self_signed(directory: &std::path::Path) -> Result<(Vec<u8>, String, String), Error> {
+        use openssl::x509::extension::SubjectAlternativeName;
+        let key = openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048)?)?;
+        let mut name = openssl::x509::X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", "localhost")?;
+        let name = name.build();
+        let mut builder = openssl::x509::X509Builder::new()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&*openssl::bn::BigNum::from_u32(1)?.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&*openssl::asn1::Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&*openssl::asn1::Asn1Time::days_from_now(1)?)?;
+        let alternative_name = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(alternative_name)?;
+        builder.sign(&key, openssl::hash::MessageDigest::sha256())?;
+        let cert = builder.build();
+
+        std::fs::create_dir_all(directory)?;
+        let cert_path = directory.join("cert.pem");
+        let key_path = directory.join("key.pem");
+        std::fs::write(&cert_path, cert.to_pem()?)?;
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8()?)?;
+        Ok((
+            cert.to_der()?,
+            cert_path.to_string_lossy().to_string(),
+            key_path.to_string_lossy().to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_routes() -> Result<(), Error> {
+        let proxy = ReverseProxy::new(&[
+            route("/", "http://web.test", false),
+            route("/api", "https://api.test:8443/v1/", true),
+        ])?;
+        let rewrite = |path: &str| -> Result<String, Error> {
+            let uri: Uri = path.parse()?;
+            Ok(proxy.route(uri.path()).unwrap().rewrite(&uri)?.to_string())
+        };
+        assert_eq!(
+            rewrite("/api/users?page=2")?,
+            "https://api.test:8443/v1/users?page=2"
+        );
+        assert_eq!(rewrite("/api")?, "https://api.test:8443/v1/");
+        assert_eq!(rewrite("/apiary")?, "http://web.test/apiary");
+        assert_eq!(rewrite("/")?, "http://web.test/");
+
+        assert!(ReverseProxy::new(&[]).is_err());
+        assert!(ReverseProxy::new(&[route("api", "http://api.test", false)]).is_err());
+        assert!(ReverseProxy::new(&[route("/", "ftp://api.test", false)]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy() -> Result<(), Error> {
+        let api = upstream("api").await;
+        let web = upstream("web").await;
+        let memory = Arc::new(Memory::new(10));
+        let reverse = ReverseProxy::new(&[
+            route("/api", &format!("http://{}/v1", api), true),
+            route("/", &format!("http://{}", web), false),
+        ])?;
+        let proxy = ProxyBuilder::new()
+            .listener(Listener::reverse(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                reverse,
+            ))
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        let client = hyper::Client::new();
+        let response = client
+            .get(format!("http://{}/api/users?page=2", proxy.addr()).parse()?)
+            .await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, format!("api /v1/users?page=2 {}", api));
+        let response = client
+            .get(format!("http://{}/index.html", proxy.addr()).parse()?)
+            .await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, format!("web /index.html {}", web));
+
+        let addr = proxy.addr();
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(2);
+        assert_eq!(recorded.len(), 2);
+        let api_traffic = recorded
+            .iter()
+            .find(|traffic| traffic.path == "/v1/users")
+            .unwrap();
+        assert_eq!(api_traffic.query, "page=2");
+        assert_eq!(api_traffic.request_headers["host"], api.to_string());
+        assert_eq!(
+            api_traffic.request_headers["x-forwarded-host"],
+            addr.to_string()
+        );
+        assert_eq!(api_traffic.request_headers["x-forwarded-proto"], "http");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_dead_route() -> Result<(), Error> {
+        // Bound and dropped, so nothing is listening on the port.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let memory = Arc::new(Memory::new(10));
+        let reverse = ReverseProxy::new(&[route("/", &format!("http://{}", closed), false)])?;
+        let proxy = ProxyBuilder::new()
+            .listener(Listener::reverse(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                reverse,
+            ))
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        let response = hyper::Client::new()
+            .get(format!("http://{}/status", proxy.addr()).parse()?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let summary = proxy.shutdown().await?;
+        assert_eq!(summary.accepted, 0);
+        assert!(memory.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy_tls() -> Result<(), Error> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let web = upstream("web").await;
+        let memory = Arc::new(Memory::new(10));
+        let directory = std::env::temp_dir().join(format!("ohm-reverse-{}", std::process::id()));
+        let (cert_der, cert_path, key_path) = self_signed(&directory)?;
+        let reverse = ReverseProxy::new(&[route("/", &format!("http://{}", web), false)])?
+            .with_tls(&cert_path, &key_path)?;
+        let proxy = ProxyBuilder::new()
+            .listener(Listener::reverse(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                reverse,
+            ))
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(cert_der))?;
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(proxy.addr()).await?;
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
+            .connect("localhost".try_into()?, stream)
+            .await?;
+        let request = "GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(String::from_utf8_lossy(&response).ends_with(&format!("web /status {}", web)));
+
+        proxy.shutdown().await?;
+        std::fs::remove_dir_all(&directory)?;
+        let recorded = memory.recent_traffic(1);
+        assert_eq!(recorded[0].path, "/status");
+        assert_eq!(recorded[0].request_headers["x-forwarded-proto"], "https");
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// The work a proxy has in flight, so shutdown can wait for it instead of dropping it.
+#[derive(Default)]
+pub struct Shutdown {
+    // Cancelled when shutdown starts - open tunnels finish the exchange in progress and close.
+    pub draining: CancellationToken,
+    // Cancelled once [net] shutdown_timeout_secs has passed - tunnels still open are dropped.
+    pub aborted: CancellationToken,
+    // CONNECT tunnels, and connections on SOCKS, transparent and reverse listeners, being served.
+    pub tunnels: TaskTracker,
+    // Captured traffic still going through the filter chain and into the datastore.
+    pub pending: TaskTracker,
+    // Handed to the datastore. Behind a [batch] queue that only means queued - the final flush
+    // reports batches that failed to write.
+    pub accepted: AtomicU64,
+    pub filtered: AtomicU64,
+    pub failed: AtomicU64,
+}
+
+impl Shutdown {
+    pub fn summary(&self, tunnels_dropped: usize, pending_dropped: usize) -> Summary {
+        Summary {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            tunnels_dropped,
+            pending_dropped,
+        }
+    }
+}
+
+// What a proxy did with the traffic it captured, reported once it has shut down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub accepted: u64,
+    pub filtered: u64,
+    pub failed: u64,
+    pub tunnels_dropped: usize,
+    pub pending_dropped: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Accepted {} exchanges for storage ({} filtered out, {} failed to store).",
+            self.accepted, self.filtered, self.failed
+        )?;
+        if self.tunnels_dropped > 0 || self.pending_dropped > 0 {
+            write!(
+                f,
+                " Gave up on {} open tunnels and {} pending stores at the timeout.",
+                self.tunnels_dropped, self.pending_dropped
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Resolves on Ctrl-C, or on SIGTERM from e.g. `docker stop`.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to listen for SIGTERM.");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,245 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::service::proxy::{intercept_stream, Destination, Tunnel};
+use crate::service::state::AppState;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const SUCCEEDED: u8 = 0x00;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// SOCKS5 (RFC 1928), for clients that only speak SOCKS.
+// Each CONNECT is intercepted like an HTTP CONNECT tunnel, so both record the same traffic.
+// With [proxy_auth], clients log in with the same users (RFC 1929) and traffic is attributed to them.
+pub async fn serve(state: Arc<AppState>, mut stream: TcpStream) -> Result<(), Error> {
+    let proxy_user = negotiate(&state, &mut stream).await?;
+    let (host, port) = read_request(&mut stream).await?;
+    // Ohm speaks to the server itself, per request, so the bound address is never meaningful.
+    reply(&mut stream, SUCCEEDED).await?;
+    // Every request goes where the client asked, whatever its Host header names.
+    let tunnel = Tunnel {
+        client: state.client.to(&host, port)?,
+        destination: Destination { host, port },
+        proxy_user,
+    };
+    intercept_stream(state, stream, tunnel).await
+}
+
+// Pick an authentication method and run it, returning who logged in.
+async fn negotiate(state: &AppState, stream: &mut TcpStream) -> Result<Option<String>, Error> {
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(format!("Not a SOCKS5 client (version {}).", greeting[0]).into());
+    }
+    let mut methods = vec![0; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match state.proxy_auth {
+        Some(_) => USERNAME_PASSWORD,
+        None => NO_AUTHENTICATION,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err("SOCKS5 client doesn't offer the required authentication method.".into());
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    let proxy_auth = match &state.proxy_auth {
+        Some(proxy_auth) => proxy_auth,
+        None => return Ok(None),
+    };
+    // RFC 1929 - VER ULEN UNAME PLEN PASSWD, with its own version number.
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    let mut username = vec![0; header[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+    let username = String::from_utf8_lossy(&username).to_string();
+    let password = String::from_utf8_lossy(&password);
+    match proxy_auth.check(&username, &password) {
+        true => {
+            stream.write_all(&[header[0], 0x00]).await?;
+            Ok(Some(username))
+        }
+        false => {
+            stream.write_all(&[header[0], 0x01]).await?;
+            Err(format!("SOCKS5 authentication failed for {:?}.", username).into())
+        }
+    }
+}
+
+// Read a CONNECT request, returning the host and port the client asked for.
+async fn read_request(stream: &mut TcpStream) -> Result<(String, u16), Error> {
+    // VER CMD RSV ATYP
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let host = match header[3] {
+        0x01 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        0x03 => {
+            let mut name = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).to_string()
+        }
+        0x04 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        address_type => {
+            reply(stream, ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(format!("Unknown SOCKS5 address type {}.", address_type).into());
+        }
+    };
+    let port = stream.read_u16().await?;
+    if header[1] != CONNECT {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        return Err(format!("Unsupported SOCKS5 command {}.", header[1]).into());
+    }
+    Ok((host, port))
+}
+
+async fn reply(stream: &mut TcpStream, status: u8) -> Result<(), std::io::Error> {
+    // VER REP RSV ATYP BND.ADDR BND.PORT, with an all-zero IPv4 address.
+    stream
+        .write_all(&[VERSION, status, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::service::ca::CA;
+    use crate::service::config;
+    use crate::service::listener::Listener;
+    use crate::service::upstream::tls_upstream;
+    use crate::ProxyBuilder;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls;
+    use tokio_socks::tcp::Socks5Stream;
+
+    // A raw HTTP server that answers one request with PONG!
+    async fn upstream() -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nPONG!";
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+        Ok(addr)
+    }
+
+    // Ohm as a SOCKS proxy for alice, trusting the upstream CA if there is one.
+    async fn proxy(
+        memory: Arc<Memory>,
+        ca: CA,
+        upstream_ca_path: Option<PathBuf>,
+    ) -> Result<crate::ProxyHandle, Error> {
+        let mut config = config::Config::default();
+        config.net.upstream_ca_path =
+            upstream_ca_path.map(|path| path.to_string_lossy().to_string());
+        ProxyBuilder::from_config(config)
+            .listener(Listener::socks5(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .ca(ca)
+            .datastore(memory)
+            .proxy_auth(config::ProxyAuth {
+                realm: "ohm".to_string(),
+                users: vec![config::ProxyUser {
+                    username: "alice".to_string(),
+                    password: "hunter2".to_string(),
+                }],
+            })
+            .start()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_socks_plaintext() -> Result<(), Error> {
+        let upstream = upstream().await?;
+        let memory = Arc::new(Memory::new(10));
+        let proxy = proxy(memory.clone(), CA::generate()?, None).await?;
+
+        let refused =
+            Socks5Stream::connect_with_password(proxy.addr(), upstream, "alice", "wrong").await;
+        assert!(refused.is_err());
+
+        // A virtual host name that doesn't resolve - the request still goes where the client asked.
+        let mut stream =
+            Socks5Stream::connect_with_password(proxy.addr(), upstream, "alice", "hunter2").await?;
+        let request = "GET /ping HTTP/1.1\r\nHost: vhost.invalid\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.ends_with("PONG!"));
+
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(1);
+        assert_eq!(recorded[0].scheme, "http");
+        assert_eq!(recorded[0].host, "vhost.invalid");
+        assert_eq!(recorded[0].path, "/ping");
+        assert_eq!(recorded[0].status, 200);
+        assert_eq!(recorded[0].proxy_user, Some("alice".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks_tls() -> Result<(), Error> {
+        let (upstream, upstream_ca_path) = tls_upstream().await?;
+        let memory = Arc::new(Memory::new(10));
+        let ca = CA::generate()?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(ca.cert_der()?))?;
+        let proxy = proxy(memory.clone(), ca, Some(upstream_ca_path)).await?;
+
+        // The client trusts the minted certificate, and Ohm trusts the server's.
+        let stream = Socks5Stream::connect_with_password(
+            proxy.addr(),
+            ("localhost", upstream.port()),
+            "alice",
+            "hunter2",
+        )
+        .await?;
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
+            .connect("localhost".try_into()?, stream)
+            .await?;
+        let request = "GET /secret HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.ends_with("PONG!"));
+
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(1);
+        assert_eq!(recorded[0].scheme, "https");
+        assert_eq!(recorded[0].host, "localhost");
+        assert_eq!(recorded[0].path, "/secret");
+        assert_eq!(recorded[0].status, 200);
+        assert_eq!(recorded[0].response_body, b"PONG!");
+        Ok(())
+    }
+}
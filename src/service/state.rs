@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use crate::data::Datastore;
+use crate::service::ca::CA;
+use crate::service::config::Config;
+use crate::service::filter::Filter;
+use crate::service::proxy_auth::ProxyAuth;
+use crate::service::shutdown::Shutdown;
+use crate::service::upstream::{Upstream, UpstreamClient};
+use crate::Traffic;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// Called with every piece of traffic that passes the filter chain, before it's stored.
+pub type TrafficHook = Arc<dyn Fn(&Traffic) + Send + Sync>;
+
+// Everything one proxy instance needs, built once and shared by every connection it serves.
+// Instances don't share anything, so several can run in one process with different configs.
+pub struct AppState {
+    pub config: Config,
+    pub datastore: Arc<dyn Datastore>,
+    pub filter: Filter,
+    pub ca: CA,
+    pub proxy_auth: Option<ProxyAuth>,
+    pub upstream: Option<Arc<Upstream>>,
+    pub client: UpstreamClient,
+    // For reverse routes, which point at known services rather than the internet.
+    pub direct_client: UpstreamClient,
+    pub hooks: Vec<TrafficHook>,
+    pub shutdown: Shutdown,
+}
+
+impl AppState {
+    pub async fn new(config: Config, datastore: Arc<dyn Datastore>, ca: CA) -> Result<Self, Error> {
+        let filter = Filter::new(&config.filter, datastore.clone()).await;
+        let proxy_auth = config.proxy_auth.as_ref().map(ProxyAuth::new);
+        let upstream = match &config.upstream {
+            Some(upstream) => Some(Arc::new(Upstream::new(upstream)?)),
+            None => None,
+        };
+        let client = UpstreamClient::new(upstream.clone(), config.net.upstream_ca_path.as_deref())?;
+        let direct_client = client.direct();
+        Ok(Self {
+            config,
+            datastore,
+            filter,
+            ca,
+            proxy_auth,
+            upstream,
+            client,
+            direct_client,
+            hooks: Vec::new(),
+            shutdown: Shutdown::default(),
+        })
+    }
+}
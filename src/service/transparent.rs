@@ -0,0 +1,204 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+
+use crate::service::proxy::{intercept_stream, Destination, Tunnel};
+use crate::service::state::AppState;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// A connection that iptables/nftables REDIRECTed to Ohm, from a client that doesn't know it's
+// being proxied. Connections made straight to the listener are refused - with nowhere else to
+// send them, their requests would come back to Ohm.
+pub async fn serve(state: Arc<AppState>, stream: TcpStream) -> Result<(), Error> {
+    let destination = original_destination(&stream)
+        .map_err(|e| format!("No original destination, so not redirected: {}", e))?;
+    // Without a NAT rule, conntrack may report the listener's own address.
+    if destination == stream.local_addr()? {
+        return Err(format!(
+            "Connected to directly rather than redirected to {}.",
+            destination
+        )
+        .into());
+    }
+    serve_redirected(state, stream, destination).await
+}
+
+// TLS is intercepted with a certificate for the ClientHello's SNI, falling back to the original
+// destination address for clients that send none. Ohm makes its own requests to the original
+// destination, naming the server from the Host header, so the traffic is recorded as usual.
+pub(crate) async fn serve_redirected(
+    state: Arc<AppState>,
+    stream: TcpStream,
+    destination: SocketAddr,
+) -> Result<(), Error> {
+    let host = destination.ip().to_string();
+    let tunnel = Tunnel {
+        client: state.client.to(&host, destination.port())?,
+        destination: Destination {
+            host,
+            port: destination.port(),
+        },
+        proxy_user: None,
+    };
+    intercept_stream(state, stream, tunnel).await
+}
+
+// Where the client was connecting to before the REDIRECT rule sent it here.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn original_destination(stream: &TcpStream) -> Result<SocketAddr, std::io::Error> {
+    let socket = socket2::SockRef::from(stream);
+    let destination = match stream.local_addr()? {
+        SocketAddr::V4(_) => socket.original_dst()?,
+        SocketAddr::V6(_) => socket.original_dst_ipv6()?,
+    };
+    destination.as_socket().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "The original destination isn't an IP address.",
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn original_destination(_stream: &TcpStream) -> Result<SocketAddr, std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Transparent mode needs Linux netfilter.",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::service::ca::CA;
+    use crate::service::config::Config;
+    use crate::service::listener::{Listener, Mode};
+    use crate::service::upstream::tls_upstream;
+    use crate::ProxyBuilder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls;
+
+    // Ohm with a transparent listener, trusting the test upstream, and a client config trusting Ohm.
+    async fn proxy(
+        memory: Arc<Memory>,
+        upstream_ca_path: std::path::PathBuf,
+    ) -> Result<(crate::ProxyHandle, rustls::ClientConfig), Error> {
+        let ca = CA::generate()?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(ca.cert_der()?))?;
+        let mut config = Config::default();
+        config.net.upstream_ca_path = Some(upstream_ca_path.to_string_lossy().to_string());
+        let proxy = ProxyBuilder::from_config(config)
+            .listener(Listener {
+                mode: Mode::Transparent,
+                ..Listener::tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            })
+            .ca(ca)
+            .datastore(memory)
+            .start()
+            .await?;
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok((proxy, tls))
+    }
+
+    // There's no REDIRECT rule here, so stand in for one - a connection Ohm serves as if it had
+    // been on its way to the destination.
+    async fn redirect(
+        proxy: &crate::ProxyHandle,
+        destination: SocketAddr,
+    ) -> Result<TcpStream, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = proxy.state().clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = serve_redirected(state, stream, destination).await;
+        });
+        Ok(TcpStream::connect(addr).await?)
+    }
+
+    #[tokio::test]
+    async fn test_transparent_tls() -> Result<(), Error> {
+        let (upstream, upstream_ca_path) = tls_upstream().await?;
+        let memory = Arc::new(Memory::new(10));
+        let (proxy, tls) = proxy(memory.clone(), upstream_ca_path).await?;
+
+        // The certificate is minted from the SNI, and the request goes to the original destination.
+        let stream = redirect(&proxy, upstream).await?;
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
+            .connect("localhost".try_into()?, stream)
+            .await?;
+        let request = "GET /device HTTP/1.0\r\n\r\n";
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.ends_with("PONG!"));
+
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(1);
+        assert_eq!(recorded[0].scheme, "https");
+        assert_eq!(recorded[0].host, "localhost");
+        assert_eq!(recorded[0].path, "/device");
+        assert_eq!(recorded[0].status, 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transparent_refuses_direct() -> Result<(), Error> {
+        let (_upstream, upstream_ca_path) = tls_upstream().await?;
+        let memory = Arc::new(Memory::new(10));
+        let (proxy, _tls) = proxy(memory.clone(), upstream_ca_path).await?;
+
+        let mut stream = TcpStream::connect(proxy.addr()).await?;
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", proxy.addr());
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+
+        proxy.shutdown().await?;
+        assert!(memory.recent_traffic(1).is_empty());
+        Ok(())
+    }
+
+    // Browsers that negotiate h2 are served HTTP/2, servers that offer it are spoken to in it,
+    // and the traffic says so.
+    #[cfg(feature = "http2")]
+    #[tokio::test]
+    async fn test_transparent_h2() -> Result<(), Error> {
+        let (upstream, upstream_ca_path) = tls_upstream().await?;
+        let memory = Arc::new(Memory::new(10));
+        let (proxy, mut tls) = proxy(memory.clone(), upstream_ca_path).await?;
+
+        let stream = redirect(&proxy, upstream).await?;
+        tls.alpn_protocols = vec![b"h2".to_vec()];
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
+            .connect("localhost".try_into()?, stream)
+            .await?;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await?;
+        tokio::spawn(connection);
+        let request = hyper::Request::get("https://localhost/device").body(hyper::Body::empty())?;
+        let response = sender.send_request(request).await?;
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        drop(sender);
+
+        proxy.shutdown().await?;
+        let recorded = memory.recent_traffic(1);
+        assert_eq!(recorded[0].version, "HTTP/2.0");
+        assert_eq!(recorded[0].upstream_version.as_deref(), Some("HTTP/2.0"));
+        assert_eq!(recorded[0].host, "localhost");
+        assert_eq!(recorded[0].path, "/device");
+        Ok(())
+    }
+}
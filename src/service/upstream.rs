@@ -0,0 +1,546 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::uri::{Authority, Scheme};
+use hyper::client::connect::{Connected, Connection, HttpConnector};
+use hyper::header::{HeaderValue, HOST, PROXY_AUTHORIZATION};
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Response, Uri};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsConnector, TlsStream};
+
+use crate::service::config;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// The outbound client every proxied request goes through.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    client: Client<UpstreamConnector, Body>,
+    connector: UpstreamConnector,
+}
+
+impl UpstreamClient {
+    pub fn new(upstream: Option<Arc<Upstream>>, ca_path: Option<&str>) -> Result<Self, Error> {
+        Ok(Self::from_connector(UpstreamConnector::new(
+            upstream, ca_path,
+        )?))
+    }
+
+    fn from_connector(connector: UpstreamConnector) -> Self {
+        Self {
+            client: Client::builder().build(connector.clone()),
+            connector,
+        }
+    }
+
+    // The same client without the [upstream] proxy, connecting straight to every server.
+    pub fn direct(&self) -> Self {
+        Self::from_connector(UpstreamConnector {
+            upstream: None,
+            ..self.connector.clone()
+        })
+    }
+
+    // A client that connects to host:port whatever a request's authority says, e.g. where a SOCKS
+    // client asked to go. TLS still checks the certificate against the request's host.
+    pub fn to(&self, host: &str, port: u16) -> Result<Self, Error> {
+        let destination = match host.contains(':') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+        Ok(Self::from_connector(UpstreamConnector {
+            destination: Some(destination.parse()?),
+            ..self.connector.clone()
+        }))
+    }
+
+    // Send a request, with the Proxy-Authorization an HTTP upstream needs to forward it.
+    // Whether it goes through the upstream is decided from where the connector dials, like the
+    // connection itself, so the Host header can't send credentials to the server or skip them.
+    pub async fn request(
+        &self,
+        mut request: Request<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(upstream) = &self.connector.upstream {
+            // hyper would otherwise fill in a missing Host from the rewritten URI below.
+            if let Some(authority) = request.uri().authority() {
+                if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                    request.headers_mut().entry(HOST).or_insert(host);
+                }
+            }
+            let dial = self
+                .connector
+                .dial(request.uri())
+                .unwrap_or_else(|_| request.uri().clone());
+            if upstream.forwards(&dial) {
+                if let Some(credentials) = upstream.basic_credentials() {
+                    request
+                        .headers_mut()
+                        .insert(PROXY_AUTHORIZATION, credentials);
+                }
+                // The upstream reads the server from the absolute-form target, not the Host header.
+                *request.uri_mut() = dial;
+            }
+        }
+        self.client.request(request).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Http,
+    Socks5,
+}
+
+// An egress proxy between Ohm and the servers it proxies to.
+pub struct Upstream {
+    kind: Kind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    no_proxy: Vec<String>,
+}
+
+impl Upstream {
+    pub fn new(config: &config::Upstream) -> Result<Self, Error> {
+        let url: Uri = config
+            .url
+            .parse()
+            .map_err(|e| format!("Invalid [upstream] url {:?}: {}", config.url, e))?;
+        let kind = match url.scheme_str() {
+            Some("http") => Kind::Http,
+            // The target host name is always sent to the proxy to resolve.
+            Some("socks5") | Some("socks5h") => Kind::Socks5,
+            _ => return Err("[upstream] url must start with http:// or socks5://".into()),
+        };
+        let host = url
+            .host()
+            .ok_or("[upstream] url needs a host.")?
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let port = url.port_u16().unwrap_or(match kind {
+            Kind::Http => 8080,
+            Kind::Socks5 => 1080,
+        });
+        let credentials = match (&config.username, &config.password) {
+            (Some(username), password) => {
+                Some((username.clone(), password.clone().unwrap_or_default()))
+            }
+            (None, _) => None,
+        };
+        Ok(Self {
+            kind,
+            host,
+            port,
+            credentials,
+            no_proxy: config
+                .no_proxy
+                .iter()
+                .map(|entry| entry.trim_start_matches('.').to_lowercase())
+                .collect(),
+        })
+    }
+
+    // Whether requests to this host skip the upstream proxy.
+    pub fn bypasses(&self, host: &str) -> bool {
+        let host = host.trim_matches(|c| c == '[' || c == ']').to_lowercase();
+        self.no_proxy
+            .iter()
+            .any(|entry| entry == "*" || host == *entry || host.ends_with(&format!(".{}", entry)))
+    }
+
+    // Whether a request to this URI is sent to an HTTP upstream as is, in absolute form.
+    // HTTPS goes through a CONNECT tunnel instead, which carries its own Proxy-Authorization.
+    pub fn forwards(&self, uri: &Uri) -> bool {
+        self.kind == Kind::Http
+            && uri.scheme() != Some(&Scheme::HTTPS)
+            && !self.bypasses(uri.host().unwrap_or(""))
+    }
+
+    fn basic_credentials(&self) -> Option<HeaderValue> {
+        let (username, password) = self.credentials.as_ref()?;
+        let encoded =
+            openssl::base64::encode_block(format!("{}:{}", username, password).as_bytes());
+        HeaderValue::from_str(&format!("Basic {}", encoded)).ok()
+    }
+
+    // A stream to the server, and whether it's really to an HTTP upstream taking absolute-form requests.
+    async fn connect(&self, uri: &Uri, direct: HttpConnector) -> Result<(TcpStream, bool), Error> {
+        let host = uri
+            .host()
+            .ok_or("URI does not contain a host.")?
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        if self.bypasses(&host) {
+            return Ok((direct.clone().call(uri.clone()).await?, false));
+        }
+
+        match self.kind {
+            Kind::Http => {
+                let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+                if !https {
+                    // Plain HTTP is sent to the proxy as is, with an absolute-form request target.
+                    return Ok((stream, true));
+                }
+                self.tunnel(&mut stream, &host, port).await?;
+                Ok((stream, false))
+            }
+            Kind::Socks5 => {
+                let proxy = (self.host.as_str(), self.port);
+                let target = (host.as_str(), port);
+                let stream = match &self.credentials {
+                    Some((username, password)) => {
+                        tokio_socks::tcp::Socks5Stream::connect_with_password(
+                            proxy, target, username, password,
+                        )
+                        .await?
+                    }
+                    None => tokio_socks::tcp::Socks5Stream::connect(proxy, target).await?,
+                };
+                Ok((stream.into_inner(), false))
+            }
+        }
+    }
+
+    // Ask an HTTP upstream to CONNECT to the target, leaving the stream at the start of the tunnel.
+    async fn tunnel(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+        let authority = match host.contains(':') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some(credentials) = self.basic_credentials() {
+            request.push_str(&format!(
+                "Proxy-Authorization: {}\r\n",
+                credentials.to_str()?
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the response head a byte at a time so nothing past it is taken from the tunnel.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 8192 {
+                return Err("[upstream] CONNECT response head is too large.".into());
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(format!("[upstream] refused CONNECT {}: {}", authority, status_line).into()),
+        }
+    }
+}
+
+// Connects directly, or through the [upstream] proxy when one is configured.
+// TLS for HTTPS is added on top, so it runs end to end through any CONNECT tunnel.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    direct: HttpConnector,
+    tls: TlsConnector,
+    upstream: Option<Arc<Upstream>>,
+    // Dialled instead of the request's authority.
+    destination: Option<Authority>,
+}
+
+impl UpstreamConnector {
+    pub fn new(upstream: Option<Arc<Upstream>>, ca_path: Option<&str>) -> Result<Self, Error> {
+        let mut direct = HttpConnector::new();
+        direct.enforce_http(false);
+        let mut tls = native_tls::TlsConnector::builder();
+        if let Some(ca_path) = ca_path {
+            for cert in openssl::x509::X509::stack_from_pem(&std::fs::read(ca_path)?)? {
+                tls.add_root_certificate(native_tls::Certificate::from_der(&cert.to_der()?)?);
+            }
+        }
+        // Servers that pick h2 get HTTP/2 - hyper reads the choice from UpstreamStream::connected.
+        #[cfg(feature = "http2")]
+        tls.request_alpns(&["h2", "http/1.1"]);
+        Ok(Self {
+            direct,
+            tls: TlsConnector::from(tls.build()?),
+            upstream,
+            destination: None,
+        })
+    }
+
+    // The URI this connector dials for a request to `uri`.
+    fn dial(&self, uri: &Uri) -> Result<Uri, http::uri::InvalidUriParts> {
+        match &self.destination {
+            Some(destination) => {
+                let mut parts = uri.clone().into_parts();
+                parts.authority = Some(destination.clone());
+                Uri::from_parts(parts)
+            }
+            None => Ok(uri.clone()),
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let direct = self.direct.clone();
+        let tls = self.tls.clone();
+        let upstream = self.upstream.clone();
+        let dial = self.dial(&uri);
+        Box::pin(async move {
+            let dial = dial?;
+            let (stream, proxied) = match upstream {
+                Some(upstream) => upstream.connect(&dial, direct).await?,
+                None => (direct.clone().call(dial).await?, false),
+            };
+            if uri.scheme() != Some(&Scheme::HTTPS) {
+                return Ok(UpstreamStream::Tcp { stream, proxied });
+            }
+            let host = uri
+                .host()
+                .ok_or("URI does not contain a host.")?
+                .trim_matches(|c| c == '[' || c == ']');
+            let stream = tls.connect(host, stream).await?;
+            Ok(UpstreamStream::Tls(Box::new(stream)))
+        })
+    }
+}
+
+// A stream to the server or the upstream proxy, telling hyper which one it is talking to
+// and which protocol TLS negotiated.
+pub enum UpstreamStream {
+    Tcp { stream: TcpStream, proxied: bool },
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Tcp { stream, proxied } => stream.connected().proxy(*proxied),
+            Self::Tls(stream) => {
+                let connected = stream.get_ref().get_ref().get_ref().connected();
+                #[cfg(feature = "http2")]
+                if let Ok(Some(protocol)) = stream.get_ref().negotiated_alpn() {
+                    if protocol == b"h2" {
+                        return connected.negotiated_h2();
+                    }
+                }
+                connected
+            }
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp { stream, .. } => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp { stream, .. } => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp { stream, .. } => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp { stream, .. } => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// An HTTPS server on localhost that answers every request with PONG!, over HTTP/1.1 or h2.
+// Returns its address and a PEM file with the CA to trust for it.
+#[cfg(test)]
+pub(crate) async fn tls_upstream() -> Result<(std::net::SocketAddr, std::path::PathBuf), Error> {
+    use crate::service::ca::CA;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+
+    let ca = CA::generate()?;
+    let acceptor =
+        tokio_rustls::TlsAcceptor::from(Arc::new(ca.get_host_config("localhost").await?));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let ca_path = std::env::temp_dir().join(format!(
+        "ohm-upstream-ca-{}-{}.pem",
+        std::process::id(),
+        addr.port()
+    ));
+    std::fs::write(
+        &ca_path,
+        openssl::x509::X509::from_der(&ca.cert_der()?)?.to_pem()?,
+    )?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let service = service_fn(|_request| async {
+                        Ok::<_, hyper::Error>(Response::new(Body::from("PONG!")))
+                    });
+                    let _ = Http::new().serve_connection(stream, service).await;
+                }
+            });
+        }
+    });
+    Ok((addr, ca_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::service::ca::CA;
+    use crate::ProxyBuilder;
+
+    fn upstream(url: &str, username: Option<&str>) -> config::Upstream {
+        config::Upstream {
+            url: url.to_string(),
+            username: username.map(str::to_string),
+            password: Some("hunter2".to_string()),
+            no_proxy: vec!["localhost".to_string(), ".internal.test".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let upstream = Upstream::new(&upstream("http://127.0.0.1:3128", None)).unwrap();
+        assert!(upstream.bypasses("localhost"));
+        assert!(upstream.bypasses("internal.test"));
+        assert!(upstream.bypasses("api.INTERNAL.test"));
+        assert!(!upstream.bypasses("notinternal.test"));
+        assert!(!upstream.bypasses("foobar.com"));
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        for url in ["ftp://127.0.0.1:21", "http://", "not a url"] {
+            assert!(Upstream::new(&upstream(url, None)).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel() -> Result<(), Error> {
+        // Another Ohm stands in for the corporate proxy.
+        let egress = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(Arc::new(Memory::new(10)))
+            .proxy_auth(config::ProxyAuth {
+                realm: "egress".to_string(),
+                users: vec![config::ProxyUser {
+                    username: "alice".to_string(),
+                    password: "hunter2".to_string(),
+                }],
+            })
+            .start()
+            .await?;
+        let url = format!("http://{}", egress.addr());
+        let target: Uri = "https://foobar.com/".parse()?;
+
+        let direct = HttpConnector::new();
+        let authenticated = Upstream::new(&upstream(&url, Some("alice")))?;
+        let (_stream, proxied) = authenticated.connect(&target, direct.clone()).await?;
+        assert!(!proxied);
+
+        let anonymous = Upstream::new(&upstream(&url, None))?;
+        let error = anonymous.connect(&target, direct).await.err().unwrap();
+        assert!(error.to_string().contains("407"));
+
+        egress.shutdown().await?;
+        Ok(())
+    }
+
+    // Answers one request with an empty 200 and hands back its head.
+    async fn recorder() -> Result<(u16, tokio::sync::oneshot::Receiver<String>), Error> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&head).to_lowercase());
+        });
+        Ok((port, receiver))
+    }
+
+    #[tokio::test]
+    async fn test_to_decides_from_dial_target() -> Result<(), Error> {
+        // The Host header names a bypassed host, but the client dials one the upstream handles.
+        let (proxy_port, proxy_head) = recorder().await?;
+        let upstream = Upstream::new(&upstream(
+            &format!("http://127.0.0.1:{}", proxy_port),
+            Some("alice"),
+        ))?;
+        let client = UpstreamClient::new(Some(Arc::new(upstream)), None)?;
+        let request = Request::get("http://localhost/ping")
+            .header(hyper::header::HOST, "localhost")
+            .body(Body::empty())?;
+        client.to("10.1.2.3", 8080)?.request(request).await?;
+        let head = proxy_head.await?;
+        assert!(
+            head.starts_with("get http://10.1.2.3:8080/ping "),
+            "{}",
+            head
+        );
+        assert!(head.contains("\r\nhost: localhost\r\n"), "{}", head);
+        assert!(head.contains("\r\nproxy-authorization: basic "), "{}", head);
+
+        // And the other way round - the Host header would be proxied, but the dial target isn't.
+        let (origin_port, origin_head) = recorder().await?;
+        let request = Request::get("http://foobar.com/ping")
+            .header(hyper::header::HOST, "foobar.com")
+            .body(Body::empty())?;
+        client
+            .to("localhost", origin_port)?
+            .request(request)
+            .await?;
+        let head = origin_head.await?;
+        assert!(head.starts_with("get /ping "), "{}", head);
+        assert!(!head.contains("proxy-authorization"), "{}", head);
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+use std::sync::{Arc, Mutex};
+
+use hyper::header::{SEC_WEBSOCKET_EXTENSIONS, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Request};
+use mongodb::bson::DateTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::data::Datastore;
+use crate::model::traffic::Traffic;
+use crate::model::websocket::{Direction, WebSocketMessage, WebSocketSession};
+use crate::service::proxy::process_traffic;
+use crate::service::state::AppState;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// A frame this large ends the relay rather than being held in memory.
+const MAX_FRAME_BYTES: u64 = 64 * 1024 * 1024;
+
+// Whether the client is asking to switch the connection to WebSocket.
+pub fn is_upgrade(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+// Take the client's side of the upgrade before the request is forwarded.
+// Without permessage-deflate, frames are recorded as readable as they were sent.
+pub fn prepare(request: &mut Request<Body>) -> OnUpgrade {
+    request.headers_mut().remove(SEC_WEBSOCKET_EXTENSIONS);
+    hyper::upgrade::on(request)
+}
+
+// Relay frames between client and server once both have switched protocols, recording each one.
+// Tracked like a CONNECT tunnel, so shutdown stops relaying and stores what was seen so far.
+pub fn spawn_relay(
+    state: Arc<AppState>,
+    mut handshake: Traffic,
+    client: OnUpgrade,
+    server: OnUpgrade,
+) {
+    handshake.websocket_session = Some(new_session_id());
+    let tunnels = state.shutdown.tunnels.clone();
+    let aborted = state.shutdown.aborted.clone();
+    tunnels.spawn(async move {
+        tokio::select! {
+            result = relay(state, handshake, client, server) => {
+                if let Err(e) = result {
+                    println!("[ERROR] [src/service/websocket.rs] [spawn_relay]: {:?}", e);
+                }
+            },
+            _ = aborted.cancelled() => {},
+        }
+    });
+}
+
+async fn relay(
+    state: Arc<AppState>,
+    mut handshake: Traffic,
+    client: OnUpgrade,
+    server: OnUpgrade,
+) -> Result<(), Error> {
+    // The handshake passes the filter chain like any other exchange - frames of a filtered
+    // connection are relayed but never recorded.
+    let recorded = process_traffic(&state, &mut handshake).await;
+    let (client, server) = tokio::try_join!(client, server)?;
+
+    let mut session = WebSocketSession::new(&handshake);
+    let max_websocket_bytes = state.config.net.max_websocket_bytes;
+    // The frames so far, their size, and whether one has been left out.
+    let messages = Mutex::new((Vec::new(), 0, false));
+    let record = |message: WebSocketMessage| {
+        if !recorded {
+            return;
+        }
+        let (messages, size, truncated) = &mut *messages.lock().unwrap();
+        // Whole frames only, and none after the first left out, so what's kept is a prefix.
+        if *truncated || *size + message.recorded_bytes() > max_websocket_bytes {
+            *truncated = true;
+            return;
+        }
+        *size += message.recorded_bytes();
+        messages.push(message);
+    };
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = tokio::io::split(server);
+    let draining = state.shutdown.draining.clone();
+    tokio::select! {
+        _ = futures::future::join(
+            pipe(client_read, server_write, Direction::ClientToServer, &record),
+            pipe(server_read, client_write, Direction::ServerToClient, &record),
+        ) => {},
+        _ = draining.cancelled() => {},
+    }
+    if !recorded {
+        return Ok(());
+    }
+
+    (session.messages, _, session.truncated) = messages.into_inner().unwrap();
+    session.ended_at = Some(DateTime::now());
+    let pending = state.shutdown.pending.clone();
+    pending.spawn(async move {
+        store_session(state.datastore.as_ref(), &session).await;
+    });
+    Ok(())
+}
+
+// Copy frames one way until either side closes or a frame can't be read.
+async fn pipe<R, W>(
+    mut reader: R,
+    mut writer: W,
+    direction: Direction,
+    record: &(impl Fn(WebSocketMessage) + Sync),
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Ok(Some(frame)) = read_frame(&mut reader).await {
+        if writer.write_all(&frame.raw).await.is_err() {
+            break;
+        }
+        record(WebSocketMessage::new(
+            direction,
+            frame.opcode,
+            frame.fin,
+            frame.payload,
+        ));
+    }
+    let _ = writer.shutdown().await;
+}
+
+struct Frame {
+    // The frame as it was sent, to be forwarded untouched.
+    raw: Vec<u8>,
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// RFC 6455 section 5.2. Returns None when the stream closes between frames.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, std::io::Error> {
+    let mut head = [0; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut raw = head.to_vec();
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length).await?;
+            raw.extend_from_slice(&length);
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length).await?;
+            raw.extend_from_slice(&length);
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > MAX_FRAME_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("WebSocket frame of {} bytes is too large to relay", length),
+        ));
+    }
+    let mut mask = None;
+    if head[1] & 0x80 != 0 {
+        let mut key = [0; 4];
+        reader.read_exact(&mut key).await?;
+        raw.extend_from_slice(&key);
+        mask = Some(key);
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload).await?;
+    raw.extend_from_slice(&payload);
+    if let Some(key) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[i % 4];
+        }
+    }
+    Ok(Some(Frame {
+        raw,
+        fin: head[0] & 0x80 != 0,
+        opcode: head[0] & 0x0f,
+        payload,
+    }))
+}
+
+fn new_session_id() -> String {
+    let mut id = [0; 16];
+    openssl::rand::rand_bytes(&mut id).expect("Failed to generate a session id.");
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn store_session(datastore: &dyn Datastore, session: &WebSocketSession) {
+    if let Err(e) = datastore.add_websocket_session(session).await {
+        println!(
+            "[ERROR] [src/service/websocket.rs] [store_session]: {:?}",
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::model::websocket::OPCODE_TEXT;
+    use crate::service::ca::CA;
+    use crate::ProxyBuilder;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio::net::TcpStream;
+
+    fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        let masked = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            length if length < 126 => frame.push(masked | length as u8),
+            length => {
+                frame.push(masked | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(key) => {
+                frame.extend_from_slice(&key);
+                frame.extend(
+                    payload
+                        .iter()
+                        .enumerate()
+                        .map(|(i, byte)| byte ^ key[i % 4]),
+                );
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
+
+    // Switches to WebSocket and echoes every frame back unmasked, refusing compression it
+    // should never have been offered.
+    async fn upstream() -> SocketAddr {
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|mut request: Request<Body>| async move {
+                if request.headers().contains_key(SEC_WEBSOCKET_EXTENSIONS) {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    return Ok::<_, Infallible>(response);
+                }
+                let upgrade = hyper::upgrade::on(&mut request);
+                tokio::spawn(async move {
+                    let mut upgraded = upgrade.await.unwrap();
+                    while let Ok(Some(received)) = read_frame(&mut upgraded).await {
+                        let echo = frame(received.opcode, &received.payload, None);
+                        upgraded.write_all(&echo).await.unwrap();
+                    }
+                });
+                let response = Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(UPGRADE, "websocket")
+                    .header(hyper::header::CONNECTION, "Upgrade")
+                    .body(Body::empty())
+                    .unwrap();
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    // Opens a WebSocket to the upstream through the proxy, sends "hello" and returns the echo.
+    async fn echo_through(proxy: SocketAddr, upstream: SocketAddr) -> Result<Vec<u8>, Error> {
+        let mut stream = TcpStream::connect(proxy).await?;
+        let request = format!(
+            "GET http://{0}/chat HTTP/1.1\r\nHost: {0}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await?);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+
+        stream
+            .write_all(&frame(OPCODE_TEXT, b"hello", Some([1, 2, 3, 4])))
+            .await?;
+        let echo = read_frame(&mut stream).await?.unwrap();
+        stream.shutdown().await?;
+        Ok(echo.payload)
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() -> Result<(), Error> {
+        let payload = vec![b'x'; 300];
+        let sent = frame(0x2, &payload, Some([9, 8, 7, 6]));
+        let mut reader = &sent[..];
+        let received = read_frame(&mut reader).await?.unwrap();
+        assert_eq!(received.raw, sent);
+        assert_eq!(received.payload, payload);
+        assert_eq!(received.opcode, 0x2);
+        assert!(received.fin);
+        assert!(read_frame(&mut reader).await?.is_none());
+
+        let mut oversized = vec![0x82, 127];
+        oversized.extend_from_slice(&(MAX_FRAME_BYTES + 1).to_be_bytes());
+        assert!(read_frame(&mut &oversized[..]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_relay() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let memory = Arc::new(Memory::new(10));
+        let proxy = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        assert_eq!(echo_through(proxy.addr(), upstream).await?, b"hello");
+        proxy.shutdown().await?;
+
+        let handshake = &memory.recent_traffic(1)[0];
+        assert_eq!(handshake.status, 101);
+        let sessions = memory.websocket_sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(handshake.websocket_session, Some(sessions[0].id.clone()));
+        assert_eq!(sessions[0].path, "/chat");
+        assert!(!sessions[0].truncated);
+        let directions: Vec<Direction> = sessions[0]
+            .messages
+            .iter()
+            .map(|message| message.direction)
+            .collect();
+        assert_eq!(
+            directions,
+            vec![Direction::ClientToServer, Direction::ServerToClient]
+        );
+        for message in &sessions[0].messages {
+            assert_eq!(message.payload_string, Some("hello".to_string()));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_truncated() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let memory = Arc::new(Memory::new(10));
+        // Room for "hello" once, raw and decoded, but not for the echo.
+        let mut config = crate::service::config::Config::default();
+        config.net.max_websocket_bytes = 15;
+        let proxy = ProxyBuilder::from_config(config)
+            .ca(CA::generate()?)
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        assert_eq!(echo_through(proxy.addr(), upstream).await?, b"hello");
+        proxy.shutdown().await?;
+
+        let sessions = memory.websocket_sessions();
+        assert!(sessions[0].truncated);
+        assert_eq!(sessions[0].messages.len(), 1);
+        assert_eq!(sessions[0].messages[0].direction, Direction::ClientToServer);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_filtered() -> Result<(), Error> {
+        let upstream = upstream().await;
+        let memory = Arc::new(Memory::new(10));
+        let proxy = ProxyBuilder::new()
+            .ca(CA::generate()?)
+            .filter(crate::service::config::Filter {
+                allow_list_hosts: vec![],
+                deny_list_hosts: vec!["127.0.0.1".to_string()],
+                identity_providers: vec![],
+            })
+            .datastore(memory.clone())
+            .start()
+            .await?;
+
+        assert_eq!(echo_through(proxy.addr(), upstream).await?, b"hello");
+        proxy.shutdown().await?;
+        assert!(memory.is_empty());
+        assert!(memory.websocket_sessions().is_empty());
+        Ok(())
+    }
+}